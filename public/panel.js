@@ -12,11 +12,28 @@
 
     const STR_NEXT_FILES = 'next files >';
     const STR_PREV_FILES = '< prev files';
+    const STR_VERSIONS = ' (:n versions)';
 
     const CURRENT_PARAMS = new URL(window.location.href).searchParams;
     const CURRENT_SCOPE = CURRENT_PARAMS.get('scope') || SCOPE_COMMON;
     const CURRENT_CURSOR = parseInt(CURRENT_PARAMS.get('cursor') || 0);
 
+    function filenameFromDisposition(header) {
+        const encoded = /filename\*=UTF-8''([^;]+)/i.exec(header || '');
+
+        if (encoded) {
+            try {
+                return decodeURIComponent(encoded[1]);
+            } catch (e) {
+                // fall through to the plain name
+            }
+        }
+
+        const plain = /filename="([^"]*)"/i.exec(header || '');
+
+        return plain ? plain[1] : null;
+    }
+
     async function downloadFile(file, ev) {
         ev.preventDefault();
 
@@ -50,7 +67,7 @@
                     const url = URL.createObjectURL(req.response);
                     const tmpAnchorEl = document.createElement('a');
                     tmpAnchorEl.href = url;
-                    tmpAnchorEl.download = file.name;
+                    tmpAnchorEl.download = filenameFromDisposition(req.getResponseHeader('Content-Disposition')) || file.name;
 
                     document.body.appendChild(tmpAnchorEl);
                     tmpAnchorEl.click();
@@ -96,6 +113,10 @@
             linkEl.addEventListener('click', downloadFile.bind(null, file));
 
             listItemEl.append(linkEl);
+
+            if (file.versions > 1) {
+                listItemEl.append(STR_VERSIONS.replace(':n', file.versions));
+            }
             listGrpEl.append(listItemEl);
         }
 
@@ -3,7 +3,7 @@
 
     const ICON_UPLOAD = '📤';
     const ICON_DOWNLOAD = '📥';
-    const STR_DROP_FILE = 'Drop file here or select one to upload';
+    const STR_DROP_FILE = 'Drop files here or select some to upload';
     const UPLOAD_SUCCESS = 0;
     const UPLOAD_ABORT = 1;
     const UPLOAD_ERROR = 3;
@@ -12,6 +12,7 @@
         [UPLOAD_ABORT]: 'File :f upload cancelled',
         [UPLOAD_ERROR]: 'File :f failed to upload. :e'
     };
+    const STR_PARTIAL_UPLOAD = ':n of :t files uploaded, failed: :f';
     const ICONMAP_FILE_STATE = {
         [UPLOAD_SUCCESS]: '✔',
         [UPLOAD_ABORT]: '🟥',
@@ -22,6 +23,7 @@
     const dropIcon = document.querySelector('[data-upload-icon]');
     const dropCont = document.querySelector('[data-upload-drop]');
     const fileInput = document.querySelector('[data-file]');
+    const recipientInput = document.querySelector('[data-recipient]');
     const uploadText = document.querySelector('[data-upload-text]');
 
     function parseJson(str) {
@@ -34,21 +36,31 @@
 
     function setUploading() {
         fileInput.disabled = true;
+        recipientInput.disabled = true;
         dropCont.setAttribute('data-uploading', '');
         dropIcon.textContent = ICON_UPLOAD;
     }
 
     function unlockUpload() {
         fileInput.disabled = false;
+        recipientInput.disabled = false;
         dropCont.removeAttribute('data-uploading');
         dropIcon.textContent = ICON_DOWNLOAD;
         uploadText.textContent = STR_DROP_FILE;
         fileInput.value = '';
     }
 
-    function setReadyToUpload(state, extra) {
-        uploadText.textContent = STRMAP_FILE_STATE[state]
-            .replace(':f', fileInput.files[0].name)
+    function describeFiles() {
+        const names = Array.from(fileInput.files).map(function getName(file) {
+            return file.name;
+        });
+
+        return names.join(', ');
+    }
+
+    function setReadyToUpload(state, extra, text) {
+        uploadText.textContent = text || STRMAP_FILE_STATE[state]
+            .replace(':f', describeFiles())
             .replace(':e', extra || '');
 
         dropIcon.textContent = ICONMAP_FILE_STATE[state];
@@ -68,7 +80,14 @@
 
     fileInput.addEventListener('input', function handleUpload() {
         const formData = new FormData();
-        formData.append('file', fileInput.files[0]);
+
+        for (const file of fileInput.files) {
+            formData.append('file', file);
+        }
+
+        if (recipientInput.value.trim()) {
+            formData.append('recipient', recipientInput.value.trim());
+        }
 
         const req = new XMLHttpRequest();
 
@@ -84,6 +103,21 @@
             if (req.readyState === XMLHttpRequest.DONE) {
                 if (req.status === 200) {
                     setReadyToUpload(UPLOAD_SUCCESS);
+                } else if (req.status === 207) {
+                    const data = parseJson(req.response);
+                    const results = (data && data.files) || [];
+                    const failed = results.filter(function isFailed(result) {
+                        return result.status !== 200;
+                    });
+                    const text = STR_PARTIAL_UPLOAD
+                        .replace(':n', results.length - failed.length)
+                        .replace(':t', results.length)
+                        .replace(':f', failed.map(function describeFailure(result) {
+                            return `${result.name} (${result.error})`;
+                        }).join(', '));
+
+                    setReadyToUpload(UPLOAD_ERROR, null, text);
+                    console.error('failed to upload some files', failed);
                 } else {
                     const data = parseJson(req.response);
 
@@ -93,14 +127,14 @@
                         setReadyToUpload(UPLOAD_ERROR, UNKNOWN_ERROR);
                     }
 
-                    console.error('failed to upload %s, status %d', fileInput.files[0].name, req.status, req.response);
+                    console.error('failed to upload %s, status %d', describeFiles(), req.status, req.response);
                 }
             }
         });
 
         req.upload.addEventListener('error', function onUploadError(ev) {
             setReadyToUpload(UPLOAD_ERROR);
-            console.error('failed to upload %s', fileInput.files[0].name, ev);
+            console.error('failed to upload %s', describeFiles(), ev);
         });
 
         req.upload.addEventListener('abort', function onUploadAbort() {
@@ -0,0 +1,390 @@
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rocket::{Request, State};
+use rocket::http::Status;
+use rocket::outcome::try_outcome;
+use rocket::request::{FromRequest, Outcome};
+use chrono::{DateTime, Utc};
+use rocket::serde::json::{json, Json, Value};
+use serde::{Deserialize, Deserializer};
+
+use crate::AppState;
+use crate::auth::{new_token, PasswordReset, RESET_LIFESPAN};
+use crate::files::UserToken;
+use crate::user::{FilePrefix, User};
+
+pub struct AdminToken {
+    user: Arc<User>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminToken {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let ut = try_outcome!(request.guard::<UserToken>().await);
+
+        if !ut.user.is_admin() {
+            return Outcome::Failure((Status::Forbidden, "admin role required"));
+        }
+
+        Outcome::Success(AdminToken {
+            user: ut.user,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewUser {
+    username: String,
+    password: String,
+
+    #[serde(default)]
+    file_prefixes: Vec<FilePrefix>,
+    #[serde(default)]
+    roles: Vec<Arc<str>>,
+    #[serde(default)]
+    groups: Vec<Arc<str>>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserUpdate {
+    file_prefixes: Option<Vec<FilePrefix>>,
+    roles: Option<Vec<Arc<str>>>,
+    groups: Option<Vec<Arc<str>>>,
+    disabled: Option<bool>,
+    /// Missing leaves the expiry alone, `null` removes it.
+    #[serde(default, deserialize_with = "present")]
+    expires_at: Option<Option<DateTime<Utc>>>,
+}
+
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+    where D: Deserializer<'de>, T: Deserialize<'de>
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+pub struct NewPassword {
+    password: String,
+}
+
+fn error(status: Status, message: &str) -> (Status, Value) {
+    (status, json!({
+        "error": message
+    }))
+}
+
+fn describe(user: &User) -> Value {
+    json!({
+        "username": user.username(),
+        "filePrefixes": user.file_prefixes(),
+        "roles": user.roles(),
+        "groups": user.groups(),
+        "disabled": user.is_disabled(),
+        "expiresAt": user.expires_at(),
+    })
+}
+
+async fn find_user(state: &AppState, username: &str) -> Result<User, (Status, Value)> {
+    state.users.read().await.list
+        .get(username)
+        .map(|user| (**user).clone())
+        .ok_or_else(|| error(Status::NotFound, "user not found"))
+}
+
+/// Checks, saves and swaps in `user` under a single write lock, so a
+/// concurrent request can't slip in between the checks and the store.
+/// New users are refused if the name was taken in the meantime.
+async fn store(state: &AppState, user: User, is_new: bool) -> Result<Value, (Status, Value)> {
+    let mut running = state.users.write().await;
+
+    match (is_new, running.list.contains_key(user.username().as_ref())) {
+        (true, true) => return Err(error(Status::Conflict, "user already exists")),
+        (false, false) => return Err(error(Status::NotFound, "user not found")),
+        _ => {}
+    }
+
+    if let Err(problems) = running.check(&user) {
+        log::debug!("rejected user '{}': {:?}", user.username(), problems);
+
+        return Err((Status::UnprocessableEntity, json!({
+            "error": "invalid user",
+            "problems": problems,
+        })));
+    }
+
+    // A new user's file may exist without being loaded, or define someone else.
+    let saved = if is_new { user.create() } else { user.save() };
+
+    if let Err(why) = saved {
+        if why.kind() == io::ErrorKind::AlreadyExists {
+            log::info!("refused to replace user file {:?}", user.get_path_to_config_file());
+
+            return Err(error(Status::Conflict, "user file already exists"));
+        }
+
+        log::warn!("failed to save user file {:?}: {}", user.get_path_to_config_file(), why);
+
+        return Err(error(Status::InternalServerError, "failed to save user"));
+    }
+
+    let description = describe(&user);
+
+    if let Err(why) = state.put_user_into(&mut running, user).await {
+        log::warn!("failed to update running users: {}", why);
+
+        return Err(error(Status::InternalServerError, "failed to save user"));
+    }
+
+    Ok(description)
+}
+
+#[get("/users")]
+pub async fn list_users(_admin: AdminToken, state: &State<AppState>) -> Value {
+    let users = state.users.read().await;
+
+    let mut list = users.list.values().collect::<Vec<_>>();
+    list.sort_by_key(|user| user.username());
+
+    json!({
+        "users": list.into_iter().map(|user| describe(user)).collect::<Vec<_>>()
+    })
+}
+
+#[post("/users", data = "<data>")]
+pub async fn create_user(admin: AdminToken, data: Json<NewUser>, state: &State<AppState>) -> Result<(Status, Value), (Status, Value)> {
+    if !User::is_valid_username(&data.username) {
+        return Err(error(Status::BadRequest, "invalid username"));
+    }
+
+    if data.password.is_empty() {
+        return Err(error(Status::BadRequest, "password empty"));
+    }
+
+    let mut user = User::new(&data.username);
+
+    user.set_prefixes(data.file_prefixes.clone());
+    user.set_roles(data.roles.clone());
+    user.set_groups(data.groups.clone());
+    user.set_expires_at(data.expires_at);
+
+    if let Err(why) = user.set_password(&data.password, &state.config) {
+        log::debug!("password of user '{}' not set: {}", user.username(), why);
+
+        return Err(error(why.status(), &why.to_string()));
+    }
+
+    let description = store(state, user, true).await?;
+
+    log::info!("admin '{}' created user '{}'", admin.user.username(), data.username);
+
+    Ok((Status::Created, description))
+}
+
+#[patch("/users/<username>", data = "<data>")]
+pub async fn update_user(admin: AdminToken, username: &str, data: Json<UserUpdate>, state: &State<AppState>) -> Result<Value, (Status, Value)> {
+    let mut user = find_user(state, username).await?;
+
+    if let Some(prefixes) = &data.file_prefixes {
+        user.set_prefixes(prefixes.clone());
+    }
+
+    if let Some(roles) = &data.roles {
+        user.set_roles(roles.clone());
+    }
+
+    if let Some(groups) = &data.groups {
+        user.set_groups(groups.clone());
+    }
+
+    if let Some(disabled) = data.disabled {
+        user.set_disabled(disabled);
+    }
+
+    if let Some(expires_at) = data.expires_at {
+        user.set_expires_at(expires_at);
+    }
+
+    let description = store(state, user, false).await?;
+
+    log::info!("admin '{}' updated user '{}'", admin.user.username(), username);
+
+    Ok(description)
+}
+
+#[put("/users/<username>/password", data = "<data>")]
+pub async fn set_password(admin: AdminToken, username: &str, data: Json<NewPassword>, state: &State<AppState>) -> Result<Value, (Status, Value)> {
+    if data.password.is_empty() {
+        return Err(error(Status::BadRequest, "password empty"));
+    }
+
+    let mut user = find_user(state, username).await?;
+
+    if let Err(why) = user.set_password(&data.password, &state.config) {
+        log::debug!("password of user '{}' not set: {}", user.username(), why);
+
+        return Err(error(why.status(), &why.to_string()));
+    }
+
+    let description = store(state, user, false).await?;
+
+    log::info!("admin '{}' set password of user '{}'", admin.user.username(), username);
+
+    Ok(description)
+}
+
+/// Hands out a one-time token the user can set a new password with at
+/// `/ajax/password/reset`. Earlier tokens of the user stop working.
+#[post("/users/<username>/reset")]
+pub async fn issue_reset(admin: AdminToken, username: &str, state: &State<AppState>) -> Result<(Status, Value), (Status, Value)> {
+    let user = find_user(state, username).await?;
+
+    if !user.is_active() {
+        return Err(error(Status::Conflict, "user is disabled or expired"));
+    }
+
+    let token = new_token();
+    let now = Instant::now();
+
+    let mut resets = state.resets.write().await;
+
+    resets.retain(|_, reset| reset.expires > now && reset.username != user.username());
+    resets.insert(token.as_str().into(), PasswordReset {
+        username: user.username(),
+        expires: now + Duration::from_secs(RESET_LIFESPAN),
+    });
+
+    log::info!("admin '{}' issued a password reset for user '{}'", admin.user.username(), username);
+
+    Ok((Status::Created, json!({
+        "token": token,
+        "expiresIn": RESET_LIFESPAN,
+    })))
+}
+
+#[delete("/users/<username>")]
+pub async fn delete_user(admin: AdminToken, username: &str, state: &State<AppState>) -> Result<Status, (Status, Value)> {
+    let user = find_user(state, username).await?;
+
+    if let Err(why) = user.delete() {
+        log::warn!("failed to delete user file {:?}: {}", user.get_path_to_config_file(), why);
+
+        return Err(error(Status::InternalServerError, "failed to delete user"));
+    }
+
+    if let Err(why) = state.remove_user(username).await {
+        log::warn!("failed to update running users: {}", why);
+
+        return Err(error(Status::InternalServerError, "failed to delete user"));
+    }
+
+    log::info!("admin '{}' deleted user '{}'", admin.user.username(), username);
+
+    Ok(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::{ContentType, Header};
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::json;
+
+    use crate::config::Config;
+
+    use super::*;
+
+    async fn client() -> (Client, String, String) {
+        let mut admin = User::new("admin");
+        admin.set_roles(vec!["admin".into()]);
+
+        let mut user = User::new("user");
+        user.set_prefixes(vec![FilePrefix::new("user_")]);
+
+        let state = AppState::for_tests(Config::default(), vec![admin, user]);
+
+        let admin_token = state.login_for_tests("admin").await;
+        let user_token = state.login_for_tests("user").await;
+
+        let rocket = rocket::build()
+            .manage(state)
+            .mount("/", routes![list_users, create_user, update_user]);
+
+        (Client::tracked(rocket).await.unwrap(), admin_token, user_token)
+    }
+
+    fn bearer(token: &str) -> Header<'static> {
+        Header::new("Authorization", format!("Bearer {}", token))
+    }
+
+    #[rocket::async_test]
+    async fn requires_admin_role() {
+        let (client, _, user_token) = client().await;
+
+        let response = client.get("/users").header(bearer(&user_token)).dispatch().await;
+
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[rocket::async_test]
+    async fn lists_users() {
+        let (client, admin_token, _) = client().await;
+
+        let response = client.get("/users").header(bearer(&admin_token)).dispatch().await;
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let body = json::from_str::<Value>(&response.into_string().await.unwrap()).unwrap();
+
+        assert_eq!(body["users"][1]["username"], "user");
+        assert_eq!(body["users"][1]["filePrefixes"], json!(["user_"]));
+    }
+
+    #[rocket::async_test]
+    async fn refuses_existing_username() {
+        let (client, admin_token, _) = client().await;
+
+        let response = client.post("/users")
+            .header(bearer(&admin_token))
+            .header(ContentType::JSON)
+            .body(r#"{"username": "user", "password": "long enough, really"}"#)
+            .dispatch().await;
+
+        assert_eq!(response.status(), Status::Conflict);
+
+        let body = json::from_str::<Value>(&response.into_string().await.unwrap()).unwrap();
+
+        assert_eq!(body["error"], "user already exists");
+    }
+
+    #[rocket::async_test]
+    async fn refuses_weak_password() {
+        let (client, admin_token, _) = client().await;
+
+        let response = client.post("/users")
+            .header(bearer(&admin_token))
+            .header(ContentType::JSON)
+            .body(r#"{"username": "newcomer", "password": "short"}"#)
+            .dispatch().await;
+
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+    }
+
+    #[rocket::async_test]
+    async fn refuses_to_update_missing_user() {
+        let (client, admin_token, _) = client().await;
+
+        let response = client.patch("/users/nobody")
+            .header(bearer(&admin_token))
+            .header(ContentType::JSON)
+            .body(r#"{"disabled": true}"#)
+            .dispatch().await;
+
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
@@ -9,7 +9,7 @@ use rocket::http::Status;
 use rocket::serde::json::{json, Value};
 use rocket_governor::{Method, Quota, RocketGovernable, RocketGovernor};
 
-use crate::AppState;
+use crate::{AppState, TokensVec};
 use crate::files::UserToken;
 
 #[derive(FromForm, Debug)]
@@ -18,9 +18,30 @@ pub struct LoginData<'r> {
     pass: &'r str,
 }
 
+#[derive(FromForm, Debug)]
+pub struct ChangePasswordData<'r> {
+    old: &'r str,
+    new: &'r str,
+}
+
+#[derive(FromForm, Debug)]
+pub struct ResetPasswordData<'r> {
+    token: &'r str,
+    new: &'r str,
+}
+
 pub type Token = Arc<str>;
 
-fn new_token() -> String {
+/// Seconds a password reset token stays valid.
+pub const RESET_LIFESPAN: u64 = 60 * 60;
+
+/// A one-time password reset an admin handed out for a user.
+pub struct PasswordReset {
+    pub username: Arc<str>,
+    pub expires: Instant,
+}
+
+pub fn new_token() -> String {
     use argon2::password_hash::rand_core::RngCore;
 
     const TOKEN_BYTES: usize = 32;
@@ -61,7 +82,7 @@ impl<'r> RocketGovernable<'r> for LoginRateLimitGuard {
 
 #[post("/login", data = "<form>")]
 pub async fn login(form: Form<LoginData<'_>>, state: &State<AppState>, _rt: RocketGovernor<'_, LoginRateLimitGuard>) -> Result<Value, Status> {
-    let entry = state.users.get(form.user);
+    let entry = state.users.read().await.list.get(form.user).cloned();
 
     if entry.is_none() {
         log::debug!("user '{}' not found", form.user);
@@ -69,7 +90,7 @@ pub async fn login(form: Form<LoginData<'_>>, state: &State<AppState>, _rt: Rock
         return Err(Status::Unauthorized);
     }
 
-    let user = entry.unwrap().clone();
+    let user = entry.unwrap();
 
     if !user.check_password(form.pass) {
         log::debug!("password for user '{}' mismatch", form.user);
@@ -77,6 +98,36 @@ pub async fn login(form: Form<LoginData<'_>>, state: &State<AppState>, _rt: Rock
         return Err(Status::Unauthorized);
     }
 
+    if !user.is_active() {
+        log::debug!("user '{}' is disabled or expired", form.user);
+
+        return Err(Status::Unauthorized);
+    }
+
+    let user = if user.needs_rehash(state.config.argon2()) {
+        let mut rehashed = (*user).clone();
+
+        log::info!("rehashing password of user '{}' with current parameters", form.user);
+
+        let path = rehashed.get_path_to_config_file();
+
+        let stored = match rehashed.rehash_password(form.pass, state.config.argon2()) {
+            Ok(_) => match rehashed.save() {
+                Ok(_) => state.put_user(rehashed).await.map_err(|why| why.to_string()),
+                Err(why) => Err(why.to_string()),
+            },
+            Err(why) => Err(why.to_string()),
+        };
+
+        stored.unwrap_or_else(|why| {
+            log::warn!("failed to save rehashed password {:?}: {}", path, why);
+
+            user
+        })
+    } else {
+        user
+    };
+
     let mut tokens = state.tokens.write().await;
 
     let token = new_token();
@@ -100,4 +151,211 @@ pub async fn logout(ut: UserToken, state: &State<AppState>) -> Status {
     tokens.lifespans.remove(&ut.token);
 
     Status::Ok
-}
\ No newline at end of file
+}
+
+#[post("/password", data = "<form>")]
+pub async fn change_password(ut: UserToken, form: Form<ChangePasswordData<'_>>, state: &State<AppState>, _rt: RocketGovernor<'_, LoginRateLimitGuard>) -> Result<Status, (Status, Value)> {
+    if !ut.user.check_password(form.old) {
+        log::debug!("old password for user '{}' mismatch", ut.user.username());
+
+        return Err((Status::Unauthorized, json!({
+            "error": "old password mismatch"
+        })));
+    }
+
+    if form.new.is_empty() {
+        return Err((Status::BadRequest, json!({
+            "error": "password empty"
+        })));
+    }
+
+    let mut user = (*ut.user).clone();
+
+    if let Err(why) = user.set_password(form.new, &state.config) {
+        log::debug!("password change of user '{}' failed: {}", ut.user.username(), why);
+
+        return Err((why.status(), json!({
+            "error": why.to_string()
+        })));
+    }
+
+    if let Err(why) = user.save() {
+        log::warn!("failed to save user file {:?}: {}", user.get_path_to_config_file(), why);
+
+        return Err((Status::InternalServerError, json!({
+            "error": "failed to save password"
+        })));
+    }
+
+    if let Err(why) = state.put_user(user).await {
+        log::warn!("failed to update user '{}': {}", ut.user.username(), why);
+
+        return Err((Status::InternalServerError, json!({
+            "error": "failed to save password"
+        })));
+    }
+
+    end_sessions(state, &ut.user.username(), Some(&ut.token)).await;
+
+    log::info!("user '{}' changed their password", ut.user.username());
+
+    Ok(Status::Ok)
+}
+
+/// Sets a new password with a reset token from an admin. The token works
+/// once, and all sessions of the user end.
+#[post("/password/reset", data = "<form>")]
+pub async fn reset_password(form: Form<ResetPasswordData<'_>>, state: &State<AppState>, _rt: RocketGovernor<'_, LoginRateLimitGuard>) -> Result<Status, (Status, Value)> {
+    let invalid = || (Status::Unauthorized, json!({
+        "error": "unknown or expired reset token"
+    }));
+
+    if form.new.is_empty() {
+        return Err((Status::BadRequest, json!({
+            "error": "password empty"
+        })));
+    }
+
+    let reset = state.resets.write().await.remove(form.token).ok_or_else(invalid)?;
+
+    if reset.expires <= Instant::now() {
+        return Err(invalid());
+    }
+
+    let user = state.users.read().await.list.get(&reset.username).cloned();
+
+    let mut user = match user {
+        Some(user) if user.is_active() => (*user).clone(),
+        _ => return Err(invalid()),
+    };
+
+    if let Err(why) = user.set_password(form.new, &state.config) {
+        log::debug!("password reset of user '{}' failed: {}", reset.username, why);
+
+        // A password the policy refuses shouldn't burn the token.
+        if why.status() == Status::UnprocessableEntity {
+            state.resets.write().await.insert(form.token.into(), reset);
+        }
+
+        return Err((why.status(), json!({
+            "error": why.to_string()
+        })));
+    }
+
+    if let Err(why) = user.save() {
+        log::warn!("failed to save user file {:?}: {}", user.get_path_to_config_file(), why);
+
+        return Err((Status::InternalServerError, json!({
+            "error": "failed to save password"
+        })));
+    }
+
+    if let Err(why) = state.put_user(user).await {
+        log::warn!("failed to update user '{}': {}", reset.username, why);
+
+        return Err((Status::InternalServerError, json!({
+            "error": "failed to save password"
+        })));
+    }
+
+    end_sessions(state, &reset.username, None).await;
+
+    log::info!("user '{}' reset their password", reset.username);
+
+    Ok(Status::Ok)
+}
+
+/// Ends the sessions of `username`, except for `keep`.
+async fn end_sessions(state: &AppState, username: &str, keep: Option<&Token>) {
+    let mut tokens = state.tokens.write().await;
+    let TokensVec { list, lifespans } = &mut *tokens;
+
+    list.retain(|token, user| {
+        if user.username().as_ref() != username || Some(token) == keep {
+            return true;
+        }
+
+        lifespans.remove(token);
+        false
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::ContentType;
+    use rocket::local::asynchronous::Client;
+
+    use crate::config::Config;
+    use crate::user::User;
+
+    use super::*;
+
+    async fn client(expires: Instant) -> Client {
+        let state = AppState::for_tests(Config::default(), vec![User::new("user")]);
+
+        state.resets.write().await.insert("secret".into(), PasswordReset {
+            username: "user".into(),
+            expires,
+        });
+
+        let rocket = rocket::build()
+            .manage(state)
+            .mount("/", routes![reset_password]);
+
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    async fn reset(client: &Client, token: &str, new: &str) -> Status {
+        // The rate limiter refuses requests it can't tell the origin of.
+        client.post("/password/reset")
+            .remote("127.0.0.1:8000".parse().unwrap())
+            .header(ContentType::Form)
+            .body(format!("token={}&new={}", token, new))
+            .dispatch().await
+            .status()
+    }
+
+    #[rocket::async_test]
+    async fn refuses_unknown_token() {
+        let client = client(Instant::now() + Duration::from_secs(60)).await;
+
+        assert_eq!(reset(&client, "guess", "long enough, really").await, Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn refuses_expired_token() {
+        let client = client(Instant::now()).await;
+
+        assert_eq!(reset(&client, "secret", "long enough, really").await, Status::Unauthorized);
+        assert!(client.rocket().state::<AppState>().unwrap().resets.read().await.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn refuses_login_with_unparseable_hash() {
+        let user = toml::from_str::<User>(r#"
+            username = "user"
+            file_prefixes = []
+            hashed_password = "plain"
+        "#).unwrap();
+
+        let state = AppState::for_tests(Config::default(), vec![user]);
+        let client = Client::tracked(rocket::build().manage(state).mount("/", routes![login])).await.unwrap();
+
+        let status = client.post("/login")
+            .remote("127.0.0.1:8000".parse().unwrap())
+            .header(ContentType::Form)
+            .body("user=user&pass=plain")
+            .dispatch().await
+            .status();
+
+        assert_eq!(status, Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn keeps_token_after_policy_rejection() {
+        let client = client(Instant::now() + Duration::from_secs(60)).await;
+
+        assert_eq!(reset(&client, "secret", "short").await, Status::UnprocessableEntity);
+        assert!(client.rocket().state::<AppState>().unwrap().resets.read().await.contains_key("secret"));
+    }
+}
@@ -0,0 +1,148 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where the contents hashing to `sha256` live. Files in the scopes are
+/// hard links to their blob, so a blob's link count tells how many scoped
+/// files still refer to it.
+pub fn get_path_to_blob(sha256: &str) -> PathBuf {
+    let mut path = crate::get_path_to_storage();
+
+    path.push("blobs");
+    path.push(&sha256[..2]);
+    path.push(sha256);
+
+    path
+}
+
+/// Makes sure a blob with `file`'s contents exists, adding `file` as the
+/// blob if it's the first of its kind. Callers hold the digest's lock
+/// until the blob is linked into a scope, or `release` could free it
+/// in between.
+#[cfg(unix)]
+pub async fn insert(file: &Path, sha256: &str) -> io::Result<PathBuf> {
+    let path = get_path_to_blob(sha256);
+
+    tokio::fs::create_dir_all(path.parent().expect("blob path without folder")).await?;
+
+    match tokio::fs::hard_link(file, &path).await {
+        Ok(()) => {
+            log::debug!("stored new blob {}", sha256);
+
+            Ok(path)
+        }
+        Err(why) if why.kind() == io::ErrorKind::AlreadyExists => {
+            log::debug!("reusing blob {}", sha256);
+
+            Ok(path)
+        }
+        Err(why) => Err(why),
+    }
+}
+
+/// Without link counts there's no telling when a blob is unused, so the
+/// store is off and files are stored as they are.
+#[cfg(not(unix))]
+pub async fn insert(file: &Path, _sha256: &str) -> io::Result<PathBuf> {
+    Ok(file.to_path_buf())
+}
+
+/// Frees the blob once no scoped file links to it anymore. Files stored
+/// before the blob store existed have no blob, which is fine. Callers hold
+/// the digest's lock.
+#[cfg(unix)]
+pub async fn release(sha256: &str) {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = get_path_to_blob(sha256);
+
+    let result = match tokio::fs::metadata(&path).await {
+        Ok(metadata) if metadata.nlink() <= 1 => tokio::fs::remove_file(&path).await,
+        Ok(_) => Ok(()),
+        Err(why) => Err(why),
+    };
+
+    match result {
+        Ok(()) => {}
+        Err(why) if why.kind() == io::ErrorKind::NotFound => {}
+        Err(why) => log::warn!("failed to release blob {}: {}", sha256, why),
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn release(_sha256: &str) {}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::auth::new_token;
+    use crate::lock::KeyedLocks;
+    use crate::upload::Staged;
+
+    use super::*;
+
+    async fn scratch_file() -> PathBuf {
+        let path = Staged::new_path();
+
+        tokio::fs::create_dir_all(Staged::get_path_to_staging_folder()).await.unwrap();
+        tokio::fs::write(&path, b"contents").await.unwrap();
+
+        path
+    }
+
+    #[rocket::async_test]
+    async fn frees_blobs_with_their_last_link() {
+        let sha256 = new_token();
+        let file = scratch_file().await;
+
+        let blob = insert(&file, &sha256).await.unwrap();
+        let link = Staged::new_path();
+
+        tokio::fs::hard_link(&blob, &link).await.unwrap();
+        tokio::fs::remove_file(&file).await.unwrap();
+
+        release(&sha256).await;
+        assert!(blob.exists());
+
+        tokio::fs::remove_file(&link).await.unwrap();
+
+        release(&sha256).await;
+        assert!(!blob.exists());
+    }
+
+    /// Stores and withdraws the same contents concurrently, the way
+    /// uploads and withdrawals do. Without the lock a release could free
+    /// the blob between an insert and its link.
+    #[rocket::async_test]
+    async fn locked_inserts_and_releases_dont_race() {
+        let locks = Arc::new(KeyedLocks::default());
+        let sha256 = Arc::new(new_token());
+
+        let tasks = (0..32).map(|_| {
+            let locks = locks.clone();
+            let sha256 = sha256.clone();
+
+            tokio::spawn(async move {
+                let staged = scratch_file().await;
+                let link = Staged::new_path();
+
+                let guard = locks.lock(&sha256).await;
+                let blob = insert(&staged, &sha256).await.unwrap();
+                tokio::fs::hard_link(&blob, &link).await.unwrap();
+                tokio::fs::remove_file(&staged).await.unwrap();
+                drop(guard);
+
+                tokio::fs::remove_file(&link).await.unwrap();
+
+                let _guard = locks.lock(&sha256).await;
+                release(&sha256).await;
+            })
+        }).collect::<Vec<_>>();
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert!(!get_path_to_blob(&sha256).exists());
+    }
+}
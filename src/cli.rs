@@ -0,0 +1,306 @@
+use std::fs;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+
+use ed25519_dalek::PublicKey;
+
+use crate::config::Config;
+use crate::meta::from_hex;
+use crate::receipt::{Receipt, ReceiptSigner};
+use crate::user::{get_users, validate_users, FilePrefix, User};
+
+pub const USAGE: &str = "usage:
+    dumpster [serve]
+    dumpster user add <username> [--prefix <prefix>]... [--role <role>]... [--group <group>]... [--expires <rfc3339>]
+    dumpster user passwd <username>
+    dumpster user list
+    dumpster user disable <username>
+    dumpster user enable <username>
+    dumpster prefix check
+    dumpster receipt verify <receipt.json> [--key <hex public key>]";
+
+pub enum Command {
+    Serve,
+    UserAdd {
+        username: String,
+        prefixes: Vec<FilePrefix>,
+        roles: Vec<Arc<str>>,
+        groups: Vec<Arc<str>>,
+        expires_at: Option<DateTime<Utc>>,
+    },
+    UserPasswd(String),
+    UserList,
+    UserDisable(String, bool),
+    PrefixCheck,
+    ReceiptVerify {
+        path: String,
+        key: Option<String>,
+    },
+}
+
+pub fn parse(args: impl IntoIterator<Item=String>) -> Result<Command, String> {
+    let args = args.into_iter().collect::<Vec<String>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<&str>>();
+
+    match args.as_slice() {
+        [] | ["serve"] => Ok(Command::Serve),
+        ["user", "add", username, options @ ..] => {
+            let mut prefixes = vec![];
+            let mut roles = vec![];
+            let mut groups = vec![];
+            let mut expires_at = None;
+
+            let mut options = options.iter();
+
+            while let Some(option) = options.next() {
+                let value = options.next().ok_or_else(|| format!("missing value for '{}'", option))?;
+
+                let list = match *option {
+                    "--prefix" => {
+                        prefixes.push(FilePrefix::new(*value));
+                        continue;
+                    }
+                    "--role" => &mut roles,
+                    "--group" => &mut groups,
+                    "--expires" => {
+                        let date = DateTime::parse_from_rfc3339(value)
+                            .map_err(|why| format!("invalid expiry date '{}': {}", value, why))?;
+
+                        expires_at = Some(date.with_timezone(&Utc));
+                        continue;
+                    }
+                    _ => return Err(format!("unknown option '{}'", option)),
+                };
+
+                list.push(Arc::from(*value));
+            }
+
+            Ok(Command::UserAdd {
+                username: username.to_string(),
+                prefixes,
+                roles,
+                groups,
+                expires_at,
+            })
+        }
+        ["user", "passwd", username] => Ok(Command::UserPasswd(username.to_string())),
+        ["user", "list"] => Ok(Command::UserList),
+        ["user", "disable", username] => Ok(Command::UserDisable(username.to_string(), true)),
+        ["user", "enable", username] => Ok(Command::UserDisable(username.to_string(), false)),
+        ["prefix", "check"] => Ok(Command::PrefixCheck),
+        ["receipt", "verify", path] => Ok(Command::ReceiptVerify {
+            path: path.to_string(),
+            key: None,
+        }),
+        ["receipt", "verify", path, "--key", key] => Ok(Command::ReceiptVerify {
+            path: path.to_string(),
+            key: Some(key.to_string()),
+        }),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn read_password() -> Result<String, String> {
+    // Echo is only turned off on a terminal, piped passwords are read as is.
+    let password = rpassword::prompt_password_stderr("password: ")
+        .map_err(|why| format!("couldn't read password: {}", why))?;
+
+    if password.is_empty() {
+        return Err("password empty".to_string());
+    }
+
+    Ok(password)
+}
+
+fn load_users(config: &Config) -> Result<Vec<User>, String> {
+    let (users, errors) = get_users(config);
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+
+    Ok(users)
+}
+
+fn find_user(config: &Config, username: &str) -> Result<User, String> {
+    load_users(config)?
+        .into_iter()
+        .find(|user| user.username().as_ref() == username)
+        .ok_or_else(|| format!("user '{}' not found", username))
+}
+
+/// Validates `user` against everyone else before writing its file. A new
+/// user's file must not exist yet.
+fn save(config: &Config, user: &User, is_new: bool) -> Result<(), String> {
+    let users = load_users(config)?;
+
+    let others = users
+        .iter()
+        .filter(|x| x.username() != user.username());
+
+    let problems = validate_users(others.chain(std::iter::once(user)));
+
+    if !problems.is_empty() {
+        return Err(problems.join("\n"));
+    }
+
+    let saved = if is_new { user.create() } else { user.save() };
+
+    saved.map_err(|why| format!("failed to save {:?}: {}", user.get_path_to_config_file(), why))?;
+
+    println!("saved {:?}, send SIGHUP to a running server to pick up the change", user.get_path_to_config_file());
+
+    Ok(())
+}
+
+/// Runs an offline admin command. `Command::Serve` is handled by `main`.
+pub fn run(command: Command) -> Result<(), String> {
+    let config = Config::load();
+
+    match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::UserAdd { username, prefixes, roles, groups, expires_at } => {
+            if !User::is_valid_username(&username) {
+                return Err(format!("invalid username '{}'", username));
+            }
+
+            if load_users(&config)?.iter().any(|user| user.username().as_ref() == username) {
+                return Err(format!("user '{}' already exists", username));
+            }
+
+            let mut user = User::new(&username);
+
+            user.set_prefixes(prefixes);
+            user.set_roles(roles);
+            user.set_groups(groups);
+            user.set_expires_at(expires_at);
+            user.set_password(&read_password()?, &config)?;
+
+            save(&config, &user, true)
+        }
+        Command::UserPasswd(username) => {
+            let mut user = find_user(&config, &username)?;
+
+            user.set_password(&read_password()?, &config)?;
+
+            save(&config, &user, false)
+        }
+        Command::UserList => {
+            let mut users = load_users(&config)?;
+            users.sort_by_key(|user| user.username());
+
+            for user in users {
+                let status = if user.is_disabled() {
+                    " (disabled)"
+                } else if user.is_expired() {
+                    " (expired)"
+                } else {
+                    ""
+                };
+
+                println!(
+                    "{}{}\tprefixes: {}\troles: {}\tgroups: {}\texpires: {}",
+                    user.username(),
+                    status,
+                    user.prefixes().map(|x| x.as_ref()).collect::<Vec<_>>().join(", "),
+                    user.roles().join(", "),
+                    user.groups().join(", "),
+                    user.expires_at().map_or_else(|| "never".to_string(), |x| x.to_rfc3339()),
+                );
+            }
+
+            Ok(())
+        }
+        Command::UserDisable(username, disabled) => {
+            let mut user = find_user(&config, &username)?;
+
+            user.set_disabled(disabled);
+
+            save(&config, &user, false)
+        }
+        Command::PrefixCheck => {
+            let users = load_users(&config)?;
+
+            let mut prefixes = users
+                .iter()
+                .flat_map(|user| user.prefixes().map(move |prefix| (prefix, user.username())))
+                .collect::<Vec<_>>();
+            prefixes.sort();
+
+            for (prefix, username) in prefixes {
+                println!("{}\t{}", prefix, username);
+            }
+
+            let problems = validate_users(&users);
+
+            if !problems.is_empty() {
+                return Err(problems.join("\n"));
+            }
+
+            Ok(())
+        }
+        Command::ReceiptVerify { path, key } => {
+            let receipt = fs::read_to_string(&path)
+                .map_err(|why| format!("couldn't read {}: {}", path, why))?;
+            let receipt = rocket::serde::json::from_str::<Receipt>(&receipt)
+                .map_err(|why| format!("invalid receipt {}: {}", path, why))?;
+
+            let public_key = match key {
+                Some(key) => from_hex(&key)
+                    .and_then(|key| PublicKey::from_bytes(&key).ok())
+                    .ok_or_else(|| "invalid public key".to_string())?,
+                None => *ReceiptSigner::load()
+                    .map_err(|why| format!("couldn't load receipt signing key: {}", why))?
+                    .public_key(),
+            };
+
+            if !receipt.verify(&public_key) {
+                return Err("receipt signature invalid".to_string());
+            }
+
+            println!("receipt valid: {} ({} bytes, sha256 {}) uploaded at {}", receipt.filename, receipt.size, receipt.sha256, receipt.uploaded_at);
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<Command, String> {
+        parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn parses_user_add_options() {
+        let command = parse_str("user add alice --prefix hw1_ --role admin --group staff --expires 2030-01-01T00:00:00Z");
+
+        match command {
+            Ok(Command::UserAdd { username, prefixes, roles, groups, expires_at }) => {
+                assert_eq!(username, "alice");
+                assert_eq!(prefixes.iter().map(|x| x.prefix().as_ref()).collect::<Vec<_>>(), ["hw1_"]);
+                assert_eq!(roles, [Arc::from("admin")]);
+                assert_eq!(groups, [Arc::from("staff")]);
+                assert_eq!(expires_at.unwrap().to_rfc3339(), "2030-01-01T00:00:00+00:00");
+            }
+            _ => panic!("expected user add"),
+        }
+    }
+
+    #[test]
+    fn rejects_incomplete_options() {
+        assert_eq!(parse_str("user add alice --role").err().unwrap(), "missing value for '--role'");
+        assert_eq!(parse_str("user add alice --colour red").err().unwrap(), "unknown option '--colour'");
+        assert!(parse_str("user add alice --expires tomorrow").is_err());
+    }
+
+    #[test]
+    fn falls_back_to_usage() {
+        assert!(matches!(parse_str(""), Ok(Command::Serve)));
+        assert!(matches!(parse_str("user disable bob"), Ok(Command::UserDisable(name, true)) if name == "bob"));
+        assert_eq!(parse_str("user frobnicate").err().unwrap(), USAGE);
+    }
+}
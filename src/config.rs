@@ -0,0 +1,359 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use serde::Deserialize;
+
+use crate::files::FileScope;
+use crate::hook::Hook;
+use crate::quota::Quota;
+use crate::scan::ScanConfig;
+use crate::sniff::Sniffed;
+use crate::user::{mime_type_matches, User};
+
+fn get_path_to_config_file() -> PathBuf {
+    crate::get_path_to_storage().join("config.toml")
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    common: CommonConfig,
+    argon2: Argon2Config,
+    password_policy: PasswordPolicy,
+    quota: QuotaConfig,
+    upload: UploadConfig,
+    content: ContentConfig,
+    scan: ScanConfig,
+    hooks: Vec<Hook>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct CommonConfig {
+    acl: Option<Acl>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct UploadConfig {
+    /// Seconds an uploader can withdraw a file with their receipt's token.
+    withdraw_window: u64,
+    collision: CollisionPolicy,
+    /// Seconds a resumable upload may take before its parts are removed.
+    staging_expiry: u64,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            withdraw_window: 60 * 60,
+            collision: CollisionPolicy::Version,
+            staging_expiry: 24 * 60 * 60,
+        }
+    }
+}
+
+/// What happens when a stored name is already taken. Names carry the
+/// upload's timestamp, so this only matters within the same millisecond.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CollisionPolicy {
+    /// Fail the upload with a 409.
+    Reject,
+    /// Store as `<millis>-<name>-<n>.<ext>`.
+    Suffix,
+    /// Store under the next free timestamp, keeping the logical name.
+    Version,
+}
+
+/// Which sniffed contents each scope refuses.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ContentConfig {
+    user: ContentPolicy,
+    common: ContentPolicy,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ContentPolicy {
+    /// Sniffed types to reject, `x/*` wildcards allowed.
+    deny_types: Vec<Arc<str>>,
+    /// Reject HTML and SVG that can run scripts.
+    deny_scripts: bool,
+}
+
+impl ContentPolicy {
+    pub fn allows(&self, sniffed: &Sniffed) -> bool {
+        let denied_type = self.deny_types
+            .iter()
+            .any(|denied| mime_type_matches(denied, &sniffed.mime_type));
+
+        !denied_type && (!self.deny_scripts || !sniffed.scripted)
+    }
+}
+
+/// Default quotas; `user` can be overridden in each user file.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct QuotaConfig {
+    user: Quota,
+    common: Quota,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Acl {
+    users: Vec<Arc<str>>,
+    roles: Vec<Arc<str>>,
+    groups: Vec<Arc<str>>,
+}
+
+/// Argon2id cost parameters used for new password hashes. Memory cost is
+/// in KiB; the defaults match the `argon2` crate's defaults.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Argon2Config {
+    memory_cost: u32,
+    time_cost: u32,
+    parallelism: u32,
+}
+
+impl Default for Argon2Config {
+    fn default() -> Self {
+        Self {
+            memory_cost: Params::DEFAULT_M_COST,
+            time_cost: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl Argon2Config {
+    fn params(&self) -> Result<Params, argon2::Error> {
+        Params::new(self.memory_cost, self.time_cost, self.parallelism, None)
+    }
+
+    pub fn hasher(&self) -> Argon2<'static> {
+        let params = self.params().expect("invalid argon2 parameters");
+
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+    }
+
+    /// Whether `params` are cheaper in any dimension than the configured ones.
+    pub fn is_weaker(&self, params: &Params) -> bool {
+        params.m_cost() < self.memory_cost
+            || params.t_cost() < self.time_cost
+            || params.p_cost() < self.parallelism
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    min_length: usize,
+    disallow_username: bool,
+    /// Newline separated list of breached or common passwords.
+    common_passwords_file: Option<PathBuf>,
+
+    #[serde(skip)]
+    common_passwords: HashSet<String>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            disallow_username: true,
+            common_passwords_file: None,
+            common_passwords: HashSet::new(),
+        }
+    }
+}
+
+impl PasswordPolicy {
+    fn load_common_passwords(&mut self) {
+        let path = match &self.common_passwords_file {
+            Some(path) => path,
+            None => return,
+        };
+
+        let file_contents = fs::read_to_string(path)
+            .unwrap_or_else(|why| panic!("couldn't read common passwords file {:?}: {}", path, why));
+
+        self.common_passwords = file_contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_lowercase)
+            .collect();
+
+        log::info!("loaded {} common passwords from {:?}", self.common_passwords.len(), path);
+    }
+
+    pub fn check(&self, username: &str, password: &str) -> Result<(), String> {
+        if password.chars().count() < self.min_length {
+            return Err(format!("password must be at least {} characters long", self.min_length));
+        }
+
+        let lowercase = password.to_lowercase();
+
+        if self.disallow_username && lowercase.contains(&username.to_lowercase()) {
+            return Err("password must not contain the username".to_string());
+        }
+
+        if self.common_passwords.contains(&lowercase) {
+            return Err("password is too common".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+impl Acl {
+    pub fn allows(&self, user: &User) -> bool {
+        self.users.contains(&user.username())
+            || user.roles().iter().any(|role| self.roles.contains(role))
+            || user.groups().iter().any(|group| self.groups.contains(group))
+    }
+}
+
+impl Config {
+    pub fn load() -> Self {
+        let path = get_path_to_config_file();
+        let file_contents = fs::read_to_string(&path);
+
+        if let Err(why) = &file_contents {
+            log::info!("couldn't read config file {:?}, using defaults: {}", path, why);
+            return Self::default();
+        }
+
+        let mut config = toml::from_str::<Config>(&file_contents.unwrap())
+            .expect("invalid config file schema");
+
+        config.password_policy.load_common_passwords();
+
+        if let Err(why) = config.argon2.params() {
+            panic!("invalid argon2 parameters in {:?}: {}", path, why);
+        }
+
+        config
+    }
+
+    pub fn argon2(&self) -> &Argon2Config {
+        &self.argon2
+    }
+
+    pub fn password_policy(&self) -> &PasswordPolicy {
+        &self.password_policy
+    }
+
+    pub fn withdraw_window(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.upload.withdraw_window as i64)
+    }
+
+    pub fn staging_expiry(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.upload.staging_expiry as i64)
+    }
+
+    pub fn collision_policy(&self) -> CollisionPolicy {
+        self.upload.collision
+    }
+
+    pub fn hooks(&self) -> &[Hook] {
+        &self.hooks
+    }
+
+    pub fn scan(&self) -> &ScanConfig {
+        &self.scan
+    }
+
+    pub fn content_policy(&self, scope: FileScope) -> &ContentPolicy {
+        match scope {
+            FileScope::User => &self.content.user,
+            FileScope::Common => &self.content.common,
+        }
+    }
+
+    /// Quota of `user`'s scope, or of the common scope for `None`.
+    pub fn quota_for(&self, user: Option<&User>) -> Quota {
+        match user {
+            Some(user) => user.quota().unwrap_or(self.quota.user),
+            None => self.quota.common,
+        }
+    }
+
+    /// Whether `user` may see files in the common scope. Without an ACL
+    /// configured the common scope is open to every authenticated user.
+    pub fn can_access_common(&self, user: &User) -> bool {
+        self.common.acl
+            .as_ref()
+            .is_none_or(|acl| acl.allows(user))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::data::ByteUnit;
+
+    use super::*;
+
+    #[test]
+    fn example_config_loads() {
+        let data = fs::read_to_string("storage/.example.config.toml").unwrap();
+        let config = toml::from_str::<Config>(&data).unwrap();
+
+        assert_eq!(config.quota_for(None).max_bytes(), Some(ByteUnit::Gibibyte(1)));
+    }
+
+    fn user(username: &str, roles: &[&str], groups: &[&str]) -> User {
+        toml::from_str(&format!(
+            "username = {:?}\nfile_prefixes = []\nroles = {:?}\ngroups = {:?}",
+            username, roles, groups,
+        )).unwrap()
+    }
+
+    #[test]
+    fn content_policy_denies_types_and_scripts() {
+        let config = toml::from_str::<Config>(r#"
+            [content.common]
+            deny_types = ["image/*", "application/zip"]
+            deny_scripts = true
+        "#).unwrap();
+
+        let sniffed = |mime_type: &str, scripted| Sniffed { mime_type: mime_type.to_string(), scripted };
+
+        let common = config.content_policy(FileScope::Common);
+        let user = config.content_policy(FileScope::User);
+
+        assert!(!common.allows(&sniffed("image/png", false)));
+        assert!(!common.allows(&sniffed("text/html", true)));
+        assert!(common.allows(&sniffed("text/html", false)));
+        assert!(!common.allows(&sniffed("application/zip", false)));
+        assert!(common.allows(&sniffed("application/pdf", false)));
+        assert!(user.allows(&sniffed("image/svg+xml", true)));
+    }
+
+    #[test]
+    fn common_is_open_without_acl() {
+        assert!(Config::default().can_access_common(&user("anyone", &[], &[])));
+    }
+
+    #[test]
+    fn acl_admits_listed_users_roles_and_groups() {
+        let config = toml::from_str::<Config>(r#"
+            [common.acl]
+            users = ["alice"]
+            roles = ["staff"]
+            groups = ["class-a"]
+        "#).unwrap();
+
+        assert!(config.can_access_common(&user("alice", &[], &[])));
+        assert!(config.can_access_common(&user("bob", &["staff"], &[])));
+        assert!(config.can_access_common(&user("carol", &[], &["class-a"])));
+        assert!(!config.can_access_common(&user("mallory", &["student"], &["class-b"])));
+    }
+}
@@ -1,38 +1,39 @@
 use std::borrow::Borrow;
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
-use std::fmt::{Debug};
+use std::fmt::{Debug, Write};
+use std::io;
 use std::ops::Sub;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-use rocket::{Request};
+use chrono::{DateTime, Utc};
+use rocket::{Request, State};
 use rocket::form::{Form};
 use rocket::fs::{FileName, NamedFile};
-use rocket::http::Status;
+use rocket::http::{ContentType, Header, Status};
 use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder};
 use rocket::serde::json::{json, Value};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::AppState;
 use crate::auth::Token;
+use crate::meta::FileMeta;
 use crate::user::User;
 
-#[derive(Debug, PartialEq, FromFormField)]
+#[derive(Debug, Default, PartialEq, Clone, Copy, FromFormField, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum FileScope {
+    #[default]
     Common,
     User,
 }
 
-impl Default for FileScope {
-    fn default() -> Self {
-        FileScope::Common
-    }
-}
-
 impl FileScope {
     pub fn get_path_to_common_folder() -> PathBuf {
-        let mut path = PathBuf::from("storage");
+        let mut path = crate::get_path_to_storage();
 
         path.push("uploads");
         path.push("common");
@@ -43,11 +44,19 @@ impl FileScope {
     pub fn get_path_to_common_file(filename: impl AsRef<OsStr>) -> PathBuf {
         let mut path = Self::get_path_to_common_folder();
 
-        path.push(filename.as_ref().to_str().expect("invalid filename").to_string());
+        path.push(filename.as_ref().to_str().expect("invalid filename"));
 
         path
     }
 
+    /// Folder of this scope as seen by `user`.
+    pub fn get_path_to_folder(&self, user: &User) -> PathBuf {
+        match self {
+            Self::Common => Self::get_path_to_common_folder(),
+            Self::User => user.get_path_to_user_folder(),
+        }
+    }
+
     pub fn get_path_to_file(&self, filename: impl AsRef<OsStr>, user: Option<Arc<User>>) -> PathBuf {
         match self {
             Self::Common => Self::get_path_to_common_file(filename),
@@ -108,6 +117,15 @@ impl<'r> FromRequest<'r> for UserToken {
 
         let (token, user) = user_token.unwrap();
 
+        if !user.is_active() {
+            let token = token.clone();
+
+            tokens.list.remove(&token);
+            tokens.lifespans.remove(&token);
+
+            return Outcome::Failure((Status::Unauthorized, "account disabled or expired"));
+        }
+
         Outcome::Success(UserToken {
             user: user.clone(),
             token: token.clone(),
@@ -116,97 +134,168 @@ impl<'r> FromRequest<'r> for UserToken {
 }
 
 #[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
 struct File {
+    /// Stored name of the latest version, what downloads ask for.
     name: String,
+    logical_name: String,
+    versions: usize,
 }
 
-#[get("/files?<scope>&<cursor>")]
-pub async fn list(ut: UserToken, scope: Option<FileScope>, cursor: Option<u64>) -> Result<Value, Status> {
-    const MAX_FILES: u64 = 10;
-    let cursor = cursor.unwrap_or(0);
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Version {
+    name: String,
+    size: u64,
+    uploaded_at: Option<DateTime<Utc>>,
+    /// Collision suffix, which orders uploads made in the same millisecond.
+    #[serde(skip)]
+    attempt: u32,
+}
 
-    let path = {
-        let mut path = PathBuf::from("storage");
+/// Splits a stored `<millis>-<name>` into its upload time and logical name.
+/// Uploads sharing a logical name within a scope are versions of one file;
+/// anything not named like that is a file of its own.
+fn split_stored_name(stored: &str) -> (Option<DateTime<Utc>>, &str) {
+    let split = stored
+        .split_once('-')
+        .filter(|(_, name)| !name.is_empty())
+        .and_then(|(millis, name)| Some((millis.parse::<u64>().ok()?, name)));
+
+    match split {
+        Some((millis, name)) => (Some(DateTime::from(UNIX_EPOCH + Duration::from_millis(millis))), name),
+        None => (None, stored),
+    }
+}
 
-        path.push("uploads");
+/// Splits off the `-<n>` the suffix collision policy puts before the
+/// extension, e.g. `report-1.txt` into `report.txt` and 1.
+fn split_collision_suffix(name: &str) -> Option<(String, u32)> {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) => (stem, Some(extension)),
+        None => (name, None),
+    };
 
-        match scope.unwrap_or_default() {
-            FileScope::User => {
-                path.push("user");
-                path.push(ut.user.username().to_string());
-            }
-            FileScope::Common => path.push("common"),
-        }
+    let (stem, attempt) = stem.rsplit_once('-')?;
 
-        path
+    if stem.is_empty() || attempt.is_empty() || !attempt.bytes().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+
+    let name = match extension {
+        Some(extension) => format!("{}.{}", stem, extension),
+        None => stem.to_string(),
     };
 
-    let rdir = tokio::fs::read_dir(&path).await;
+    Some((name, attempt.parse().ok()?))
+}
 
-    if rdir.is_err() {
-        log::warn!("failed to read_dir {:?}: {:?}", &path, rdir.unwrap_err());
+/// Every stored file in `path` grouped by logical name, newest version first.
+/// A suffixed upload joins the file it collided with, which tells it apart
+/// from one that was uploaded as `report-1.txt` in the first place.
+async fn read_versions(path: &Path) -> io::Result<BTreeMap<String, Vec<Version>>> {
+    let mut rdir = tokio::fs::read_dir(path).await?;
 
-        return Ok(json!({}));
-    }
+    let mut stored = BTreeMap::<String, u64>::new();
+
+    loop {
+        let entry = rdir.next_entry().await;
+
+        if entry.borrow().is_err() {
+            log::warn!("io error while reading dir {:?} {:?}", path, entry.unwrap_err());
+
+            continue;
+        }
+
+        let entry = entry.unwrap();
 
-    let (files, more) = {
-        let mut offset = cursor;
+        if entry.borrow().is_none() {
+            break;
+        }
 
-        let mut rdir = rdir.unwrap();
+        let entry = entry.unwrap();
 
-        let mut files: Vec<File> = vec![];
+        let metadata = entry.metadata().await;
 
-        let mut current_files = 0u64;
-        let mut more = false;
+        if !metadata.as_ref().is_ok_and(|x| x.is_file()) {
+            continue;
+        }
 
-        loop {
-            let entry = rdir.next_entry().await;
+        let filename = entry.file_name();
+        let filename = filename.to_str().expect("filename with invalid chars").to_owned();
 
-            if entry.borrow().is_err() {
-                log::warn!("io error while reading dir {:?} {:?}", &path, entry.unwrap_err());
+        if filename.starts_with('.') {
+            continue;
+        }
 
-                continue;
-            }
+        stored.insert(filename, metadata.unwrap().len());
+    }
 
-            let entry = entry.unwrap();
+    let mut files = BTreeMap::<String, Vec<Version>>::new();
 
-            if entry.borrow().is_none() {
-                break;
-            }
+    for (filename, &size) in &stored {
+        let (uploaded_at, logical_name) = split_stored_name(filename);
+        let millis = &filename[..filename.len() - logical_name.len()];
 
-            let entry = entry.unwrap();
+        let (logical_name, attempt) = match split_collision_suffix(logical_name) {
+            Some((original, attempt)) if uploaded_at.is_some() && stored.contains_key(&format!("{}{}", millis, original)) => (original, attempt),
+            _ => (logical_name.to_string(), 0),
+        };
 
-            if !entry.file_type().await.map_or_else(|_| false, |x| x.is_file()) {
-                continue;
-            }
+        files.entry(logical_name).or_default().push(Version {
+            uploaded_at,
+            attempt,
+            size,
+            name: filename.clone(),
+        });
+    }
 
-            let filename = entry.file_name();
-            let filename = filename.to_str().expect("filename with invalid chars").to_owned();
+    for versions in files.values_mut() {
+        versions.sort_by(|a, b| {
+            b.uploaded_at.cmp(&a.uploaded_at)
+                .then_with(|| b.attempt.cmp(&a.attempt))
+                .then_with(|| b.name.cmp(&a.name))
+        });
+    }
 
-            if filename.starts_with('.') {
-                continue;
-            }
+    Ok(files)
+}
 
-            if current_files >= MAX_FILES {
-                more = true;
-                break;
-            }
+#[get("/files?<scope>&<cursor>")]
+pub async fn list(ut: UserToken, scope: Option<FileScope>, cursor: Option<u64>, state: &State<AppState>) -> Result<Value, Status> {
+    const MAX_FILES: u64 = 10;
+    let cursor = cursor.unwrap_or(0);
+    let scope = scope.unwrap_or_default();
 
-            if offset > 0 {
-                offset -= 1;
-                continue;
-            }
+    if scope == FileScope::Common && !state.config.can_access_common(&ut.user) {
+        log::debug!("user '{}' denied access to common scope", ut.user.username());
 
-            current_files += 1;
+        return Err(Status::Forbidden);
+    }
 
-            files.push(File {
-                name: filename,
-            })
-        }
+    let path = scope.get_path_to_folder(&ut.user);
 
-        (files, more)
-    };
+    let versions = read_versions(&path).await;
 
+    if let Err(why) = versions {
+        log::warn!("failed to read_dir {:?}: {:?}", &path, why);
+
+        return Ok(json!({}));
+    }
+
+    let versions = versions.unwrap();
+    let more = versions.len() as u64 > cursor + MAX_FILES;
+
+    let files = versions
+        .into_iter()
+        .skip(cursor as usize)
+        .take(MAX_FILES as usize)
+        .map(|(logical_name, versions)| File {
+            name: versions[0].name.clone(),
+            logical_name,
+            versions: versions.len(),
+        })
+        .collect::<Vec<_>>();
 
     Ok(json!({
         "files": files,
@@ -215,6 +304,78 @@ pub async fn list(ut: UserToken, scope: Option<FileScope>, cursor: Option<u64>)
     }))
 }
 
+/// All versions of the logical file `name`, newest first. Any of them can
+/// be fetched from `/files/download` by its stored name.
+#[get("/files/versions?<scope>&<name>")]
+pub async fn versions(ut: UserToken, scope: Option<FileScope>, name: &str, state: &State<AppState>) -> Result<Value, Status> {
+    let scope = scope.unwrap_or_default();
+
+    if scope == FileScope::Common && !state.config.can_access_common(&ut.user) {
+        log::debug!("user '{}' denied access to common scope", ut.user.username());
+
+        return Err(Status::Forbidden);
+    }
+
+    let path = scope.get_path_to_folder(&ut.user);
+
+    let versions = read_versions(&path).await.map_err(|why| {
+        log::warn!("failed to read_dir {:?}: {:?}", &path, why);
+
+        Status::NotFound
+    })?;
+
+    let versions = versions.get(name).ok_or(Status::NotFound)?;
+
+    Ok(json!({
+        "logicalName": name,
+        "versions": versions,
+    }))
+}
+
+/// A stored file sent as an attachment under the name it was uploaded as.
+pub struct Download {
+    file: NamedFile,
+    name: String,
+    /// Sniffed on upload; files stored before that go by their extension.
+    content_type: Option<ContentType>,
+}
+
+impl<'r> Responder<'r, 'static> for Download {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = self.file.respond_to(request)?;
+
+        if let Some(content_type) = self.content_type {
+            response.set_header(content_type);
+        }
+
+        response.set_header(Header::new("Content-Disposition", content_disposition(&self.name)));
+        response.set_header(Header::new("X-Content-Type-Options", "nosniff"));
+
+        Ok(response)
+    }
+}
+
+/// RFC 6266 `attachment` with a plain ASCII fallback for old clients and
+/// the exact name in `filename*`.
+fn content_disposition(name: &str) -> String {
+    let fallback = name
+        .chars()
+        .map(|c| if (c.is_ascii_graphic() || c == ' ') && c != '"' && c != '\\' { c } else { '_' })
+        .collect::<String>();
+
+    let mut encoded = String::with_capacity(name.len());
+
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            write!(&mut encoded, "%{:02X}", byte).expect("encoding of filename failed");
+        }
+    }
+
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
 #[derive(FromForm, Debug)]
 pub struct DownloadData<'r> {
     filename: &'r str,
@@ -222,7 +383,13 @@ pub struct DownloadData<'r> {
 }
 
 #[post("/files/download", data = "<form>")]
-pub async fn download_file(ut: UserToken, form: Form<DownloadData<'_>>) -> Result<Option<NamedFile>, Status> {
+pub async fn download_file(ut: UserToken, form: Form<DownloadData<'_>>, state: &State<AppState>) -> Result<Option<Download>, Status> {
+    if form.scope == FileScope::Common && !state.config.can_access_common(&ut.user) {
+        log::debug!("user '{}' denied access to common scope", ut.user.username());
+
+        return Err(Status::Forbidden);
+    }
+
     {
         let filename = form.filename.split_once('.').map_or_else(|| form.filename, |(x, _)| x);
         if !FileName::new(filename).is_safe() {
@@ -242,5 +409,144 @@ pub async fn download_file(ut: UserToken, form: Form<DownloadData<'_>>) -> Resul
         return Ok(None);
     }
 
-    Ok(file.ok())
-}
\ No newline at end of file
+    let file_meta = FileMeta::load(&path).await.ok();
+
+    let name = file_meta.as_ref()
+        .and_then(|x| x.original_name.clone())
+        .unwrap_or_else(|| form.filename.to_string());
+
+    let content_type = file_meta
+        .and_then(|x| x.sniffed_type)
+        .and_then(|x| ContentType::parse_flexible(&x));
+
+    Ok(Some(Download {
+        file: file.unwrap(),
+        name,
+        content_type,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use rocket::http::{ContentType, RawStr};
+    use rocket::local::asynchronous::Client;
+
+    use crate::config::Config;
+    use crate::upload::{self, testing::{stage, ScratchUser}};
+
+    use super::*;
+
+    #[rocket::async_test]
+    async fn ends_sessions_of_inactive_users() {
+        let mut disabled = User::new("disabled");
+        disabled.set_disabled(true);
+
+        let mut expired = User::new("expired");
+        expired.set_expires_at(Some(Utc::now() - chrono::Duration::seconds(1)));
+
+        let state = AppState::for_tests(Config::default(), vec![disabled, expired]);
+        let tokens = vec![state.login_for_tests("disabled").await, state.login_for_tests("expired").await];
+
+        let client = Client::tracked(rocket::build().manage(state).mount("/", routes![list])).await.unwrap();
+
+        for token in tokens {
+            let response = client.get("/files?scope=user")
+                .header(Header::new("Authorization", format!("Bearer {}", token)))
+                .dispatch().await;
+
+            assert_eq!(response.status(), Status::Unauthorized);
+        }
+
+        assert!(client.rocket().state::<AppState>().unwrap().tokens.read().await.list.is_empty());
+    }
+
+    #[test]
+    fn splits_stored_names() {
+        let (uploaded_at, name) = split_stored_name("1600000000000-report.txt");
+
+        assert_eq!(uploaded_at.unwrap().timestamp(), 1_600_000_000);
+        assert_eq!(name, "report.txt");
+
+        assert_eq!(split_stored_name("report.txt"), (None, "report.txt"));
+        assert_eq!(split_stored_name("1600000000000-"), (None, "1600000000000-"));
+        assert_eq!(split_stored_name("draft-report.txt"), (None, "draft-report.txt"));
+    }
+
+    #[test]
+    fn splits_collision_suffixes() {
+        assert_eq!(split_collision_suffix("report-1.txt"), Some(("report.txt".to_string(), 1)));
+        assert_eq!(split_collision_suffix("archive.tar-2.gz"), Some(("archive.tar.gz".to_string(), 2)));
+        assert_eq!(split_collision_suffix("README-3"), Some(("README".to_string(), 3)));
+
+        assert_eq!(split_collision_suffix("report.txt"), None);
+        assert_eq!(split_collision_suffix("draft-report.txt"), None);
+        assert_eq!(split_collision_suffix("-1.txt"), None);
+    }
+
+    #[rocket::async_test]
+    async fn groups_versions_newest_first() {
+        let folder = std::env::temp_dir().join(format!("dumpster-{}", crate::auth::new_token()));
+
+        tokio::fs::create_dir_all(&folder).await.unwrap();
+
+        let names = [
+            "1000-report.txt", "3000-report.txt", "3000-report-1.txt", "2000-notes.txt", "2500-notes-1.txt",
+            ".3000-report.txt.toml", "legacy.txt",
+        ];
+
+        for name in names {
+            tokio::fs::write(folder.join(name), name).await.unwrap();
+        }
+
+        let versions = read_versions(&folder).await;
+        tokio::fs::remove_dir_all(&folder).await.unwrap();
+
+        let versions = versions.unwrap()
+            .into_iter()
+            .map(|(name, versions)| (name, versions.into_iter().map(|x| x.name).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(versions, [
+            ("legacy.txt".to_string(), vec!["legacy.txt".to_string()]),
+            ("notes-1.txt".to_string(), vec!["2500-notes-1.txt".to_string()]),
+            ("notes.txt".to_string(), vec!["2000-notes.txt".to_string()]),
+            ("report.txt".to_string(), vec![
+                "3000-report-1.txt".to_string(),
+                "3000-report.txt".to_string(),
+                "1000-report.txt".to_string(),
+            ]),
+        ]);
+    }
+
+    #[test]
+    fn encodes_original_names_for_downloads() {
+        assert_eq!(content_disposition("report.pdf"), "attachment; filename=\"report.pdf\"; filename*=UTF-8''report.pdf");
+        assert_eq!(content_disposition("Übung \"1\".txt"), "attachment; filename=\"_bung _1_.txt\"; filename*=UTF-8''%C3%9Cbung%20%221%22.txt");
+    }
+
+    #[rocket::async_test]
+    async fn downloads_under_the_original_name() {
+        let scratch = ScratchUser::new().await;
+        let state = AppState::for_tests(Config::default(), vec![scratch.user.clone()]);
+
+        upload::store(&state, stage(&scratch.user, "Übung 1.txt", b"answers").await).await.unwrap();
+
+        let token = state.login_for_tests(&scratch.user.username()).await;
+        let stored = scratch.stored().await.remove(0);
+
+        let client = Client::tracked(rocket::build().manage(state).mount("/", routes![download_file])).await.unwrap();
+
+        let response = client.post("/files/download")
+            .header(ContentType::Form)
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .body(format!("scope=user&filename={}", RawStr::new(&stored).percent_encode()))
+            .dispatch().await;
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Content-Disposition"), Some(content_disposition("Übung 1.txt").as_str()));
+        assert_eq!(response.into_string().await.unwrap(), "answers");
+
+        scratch.cleanup().await;
+    }
+}
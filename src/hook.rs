@@ -0,0 +1,346 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::meta::FileMeta;
+
+/// An external check run on every upload once it's stored. It gets the
+/// file's path and metadata as JSON on stdin and may print a JSON reply
+/// such as `{"action": "tag", "tags": ["late"]}`; no output accepts.
+#[derive(Deserialize, Debug)]
+pub struct Hook {
+    name: String,
+    command: PathBuf,
+    #[serde(default)]
+    args: Vec<String>,
+    /// Seconds before the hook is killed.
+    #[serde(default = "Hook::default_timeout")]
+    timeout: u64,
+    /// Accept uploads when the hook fails instead of refusing them.
+    #[serde(default)]
+    fail_open: bool,
+}
+
+pub enum Decision {
+    Accept,
+    Tag(Vec<String>),
+    Reject(Option<String>),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    Accept,
+    Tag,
+    Reject,
+}
+
+#[derive(Deserialize)]
+struct Reply {
+    action: Action,
+    #[serde(default)]
+    tags: Vec<String>,
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Input<'a> {
+    path: &'a Path,
+    metadata: &'a FileMeta,
+}
+
+impl Hook {
+    fn default_timeout() -> u64 {
+        30
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn fail_open(&self) -> bool {
+        self.fail_open
+    }
+
+    pub async fn run(&self, path: &Path, file_meta: &FileMeta) -> io::Result<Decision> {
+        let path = tokio::fs::canonicalize(path).await?;
+
+        let input = rocket::serde::json::serde_json::to_string(&Input {
+            path: &path,
+            metadata: file_meta,
+        }).map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))?;
+
+        let mut child = Command::new(&self.command)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let mut stdin = child.stdin.take().expect("hook stdin not piped");
+
+        let output = async {
+            // Hooks that only look at the file may exit without reading it.
+            match stdin.write_all(input.as_bytes()).await {
+                Err(why) if why.kind() != io::ErrorKind::BrokenPipe => return Err(why),
+                _ => drop(stdin),
+            }
+
+            child.wait_with_output().await
+        };
+
+        let output = tokio::time::timeout(Duration::from_secs(self.timeout), output)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "hook timed out"))??;
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        if !stderr.trim().is_empty() {
+            log::info!("hook '{}': {}", self.name, stderr.trim());
+        }
+
+        if !output.status.success() {
+            return Err(io::Error::other(format!("hook exited with {}", output.status)));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+
+        if stdout.trim().is_empty() {
+            return Ok(Decision::Accept);
+        }
+
+        let reply = rocket::serde::json::serde_json::from_str::<Reply>(stdout.trim())
+            .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, format!("invalid hook reply: {}", why)))?;
+
+        Ok(match reply.action {
+            Action::Accept => Decision::Accept,
+            Action::Tag => Decision::Tag(reply.tags),
+            Action::Reject => Decision::Reject(reply.reason),
+        })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use chrono::Utc;
+    use rocket::serde::json::Value;
+
+    use crate::AppState;
+    use crate::auth::new_token;
+    use crate::config::Config;
+    use crate::upload::{self, UploadError};
+    use crate::upload::testing::{stage, unquarantine, ScratchUser};
+
+    use super::*;
+
+    /// A script in the temp folder, removed along with its saved stdin
+    /// when dropped.
+    struct Script(PathBuf);
+
+    impl std::ops::Deref for Script {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for Script {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+            let _ = std::fs::remove_file(self.0.with_extension("stdin"));
+        }
+    }
+
+    /// Writes an executable shell script, which saves its stdin next to
+    /// itself as `<script>.stdin` before running `body`.
+    fn script(body: &str) -> Script {
+        shell_script(&format!("cat > \"$0.stdin\"\n{}", body))
+    }
+
+    fn shell_script(body: &str) -> Script {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("dumpster-hook-{}", new_token()));
+
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        Script(path)
+    }
+
+    /// A file in the temp folder holding `contents`, removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(contents: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!("dumpster-{}", new_token()));
+
+            std::fs::write(&path, contents).unwrap();
+
+            Self(path)
+        }
+    }
+
+    impl std::ops::Deref for TempFile {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn stdin_of(script: &Path) -> Value {
+        let stdin = std::fs::read_to_string(script.with_extension("stdin")).unwrap();
+
+        rocket::serde::json::serde_json::from_str(&stdin).unwrap()
+    }
+
+    fn hook(command: &Path, extra: &str) -> Hook {
+        toml::from_str(&format!("name = \"check\"\ncommand = {:?}\n{}", command, extra)).unwrap()
+    }
+
+    fn config(command: &Path, extra: &str) -> Config {
+        toml::from_str(&format!("[[hooks]]\nname = \"check\"\ncommand = {:?}\n{}", command, extra)).unwrap()
+    }
+
+    fn file_meta() -> FileMeta {
+        FileMeta {
+            sha256: "ab".repeat(32),
+            size: 11,
+            uploaded_at: Utc::now(),
+            delete_token_hash: None,
+            withdraw_until: None,
+            original_name: Some("report.txt".to_string()),
+            stored_name: None,
+            uploader_ip: None,
+            user_agent: None,
+            content_type: None,
+            sniffed_type: Some("text/plain".to_string()),
+            scan_verdict: None,
+            tags: vec![],
+        }
+    }
+
+    #[rocket::async_test]
+    async fn passes_args_and_input() {
+        // Refuses to tag unless called with exactly the configured args.
+        let script = script(r#"[ "$*" = "--strict --quiet" ] || exit 3
+echo '{"action": "tag", "tags": ["checked"]}'"#);
+        let file = TempFile::new(b"some report");
+
+        let decision = hook(&script, r#"args = ["--strict", "--quiet"]"#).run(&file, &file_meta()).await.unwrap();
+
+        assert!(matches!(decision, Decision::Tag(tags) if tags == ["checked"]));
+
+        let input = stdin_of(&script);
+
+        assert_eq!(input["path"], file.canonicalize().unwrap().to_str().unwrap());
+        assert_eq!(input["metadata"]["original_name"], "report.txt");
+        assert_eq!(input["metadata"]["sniffed_type"], "text/plain");
+    }
+
+    #[rocket::async_test]
+    async fn fails_on_non_zero_exit() {
+        let script = script("echo 'not today' >&2\nexit 1");
+        let file = TempFile::new(b"some report");
+
+        let why = hook(&script, "").run(&file, &file_meta()).await.err().unwrap();
+
+        assert!(why.to_string().contains("exit status: 1"), "{}", why);
+    }
+
+    #[rocket::async_test]
+    async fn kills_slow_hooks() {
+        let script = script("sleep 5");
+        let file = TempFile::new(b"some report");
+
+        let why = hook(&script, "timeout = 1").run(&file, &file_meta()).await.err().unwrap();
+
+        assert_eq!(why.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[rocket::async_test]
+    async fn accepts_hooks_ignoring_their_input() {
+        let script = shell_script("exit 0");
+        let file = TempFile::new(b"some report");
+
+        // More than a pipe holds, so writing it outlives the hook.
+        let file_meta = FileMeta {
+            user_agent: Some("x".repeat(1 << 20)),
+            ..file_meta()
+        };
+
+        let decision = hook(&script, "").run(&file, &file_meta).await.unwrap();
+
+        assert!(matches!(decision, Decision::Accept));
+    }
+
+    #[rocket::async_test]
+    async fn sees_the_stored_file_and_records_tags() {
+        let scratch = ScratchUser::new().await;
+        let script = script(r#"echo '{"action": "tag", "tags": ["late"]}'"#);
+        let state = AppState::for_tests(config(&script, ""), vec![scratch.user.clone()]);
+
+        let receipt = upload::store(&state, stage(&scratch.user, "report.txt", b"some report").await).await.unwrap();
+
+        let path = scratch.user.get_path_to_user_file(&receipt.filename);
+        let input = stdin_of(&script);
+
+        assert_eq!(input["path"], path.canonicalize().unwrap().to_str().unwrap());
+        assert_eq!(input["metadata"]["stored_name"], receipt.filename.as_str());
+        assert_eq!(FileMeta::load(&path).await.unwrap().tags, ["late"]);
+
+        scratch.cleanup().await;
+    }
+
+    #[rocket::async_test]
+    async fn quarantines_rejected_uploads() {
+        let scratch = ScratchUser::new().await;
+        let script = script(r#"echo '{"action": "reject", "reason": "wrong format"}'"#);
+        let state = AppState::for_tests(config(&script, ""), vec![scratch.user.clone()]);
+
+        let raw_name = format!("{}.txt", &new_token()[..12]);
+        let result = upload::store(&state, stage(&scratch.user, &raw_name, b"some report").await).await;
+
+        assert!(matches!(&result, Err(UploadError::RejectedByHook(hook, Some(reason))) if hook == "check" && reason == "wrong format"));
+        assert!(scratch.stored().await.is_empty());
+        assert!(unquarantine(&raw_name).await.is_some());
+
+        scratch.cleanup().await;
+    }
+
+    #[rocket::async_test]
+    async fn takes_back_uploads_when_a_hook_fails() {
+        let scratch = ScratchUser::new().await;
+        let script = script("exit 2");
+        let state = AppState::for_tests(config(&script, ""), vec![scratch.user.clone()]);
+
+        // Contents of their own, so the blob is this upload's alone.
+        let contents = new_token();
+        let result = upload::store(&state, stage(&scratch.user, "report.txt", contents.as_bytes()).await).await;
+
+        assert!(matches!(&result, Err(UploadError::HookFailed(hook)) if hook == "check"));
+        assert_eq!(result.err().unwrap().status(), rocket::http::Status::ServiceUnavailable);
+        assert!(scratch.stored().await.is_empty());
+        assert!(!crate::blob::get_path_to_blob(&crate::meta::sha256_hex(contents.as_bytes())).exists());
+
+        let fail_open = AppState::for_tests(config(&script, "fail_open = true"), vec![scratch.user.clone()]);
+
+        assert!(upload::store(&fail_open, stage(&scratch.user, "report.txt", b"some report").await).await.is_ok());
+
+        scratch.cleanup().await;
+    }
+}
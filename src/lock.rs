@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use tokio::sync::OwnedMutexGuard;
+
+type KeyMutex = tokio::sync::Mutex<()>;
+
+/// Async locks created on demand per key, such as an upload id. A key's
+/// lock is forgotten once nobody holds or waits for it.
+#[derive(Default)]
+pub struct KeyedLocks {
+    list: Mutex<HashMap<String, Weak<KeyMutex>>>,
+}
+
+impl KeyedLocks {
+    pub async fn lock(&self, key: &str) -> OwnedMutexGuard<()> {
+        let mutex = {
+            let mut list = self.list.lock().expect("keyed locks poisoned");
+
+            list.retain(|_, mutex| mutex.strong_count() > 0);
+
+            match list.get(key).and_then(Weak::upgrade) {
+                Some(mutex) => mutex,
+                None => {
+                    let mutex = Arc::new(KeyMutex::new(()));
+
+                    list.insert(key.to_string(), Arc::downgrade(&mutex));
+
+                    mutex
+                }
+            }
+        };
+
+        mutex.lock_owned().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[rocket::async_test]
+    async fn serializes_per_key() {
+        let locks = KeyedLocks::default();
+
+        let held = locks.lock("a").await;
+
+        // Other keys aren't held up.
+        drop(locks.lock("b").await);
+
+        let waiting = tokio::time::timeout(Duration::from_millis(50), locks.lock("a")).await;
+
+        assert!(waiting.is_err());
+
+        drop(held);
+
+        let _relocked = locks.lock("a").await;
+
+        assert_eq!(locks.list.lock().unwrap().len(), 1);
+    }
+}
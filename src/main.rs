@@ -5,19 +5,58 @@ extern crate rocket;
 
 use std::collections::HashMap;
 use std::fs;
-use std::sync::Arc;
-use std::time::Instant;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
+use rocket::{Build, Rocket};
+use rocket::fairing::AdHoc;
 use rocket::fs::FileServer;
 use tokio::sync::RwLock;
 
-use crate::auth::Token;
-use crate::user::{get_users, User};
+use crate::auth::{PasswordReset, Token};
+use crate::cli::Command;
+use crate::config::Config;
+use crate::lock::KeyedLocks;
+use crate::quota::UsageVec;
+use crate::receipt::ReceiptSigner;
+use crate::upload::Staged;
+use crate::user::{get_users, validate_users, User};
 
-mod upload;
+// Modules with routes are public: the macros rocket generates for them are
+// re-exports, which are flagged as unused in private modules.
+pub mod upload;
 mod user;
-mod auth;
-mod files;
+pub mod auth;
+pub mod files;
+mod config;
+pub mod admin;
+mod cli;
+pub mod quota;
+mod meta;
+pub mod receipt;
+pub mod tus;
+mod blob;
+mod sniff;
+mod scan;
+mod hook;
+mod lock;
+
+/// Everything the server keeps lives below this folder: `storage` in the
+/// working directory unless `DUMPSTER_STORAGE` points elsewhere. Tests get
+/// one in the temp folder, so they never touch real uploads.
+pub fn get_path_to_storage() -> PathBuf {
+    static STORAGE: OnceLock<PathBuf> = OnceLock::new();
+
+    STORAGE.get_or_init(|| {
+        if cfg!(test) {
+            return std::env::temp_dir().join("dumpster-tests");
+        }
+
+        std::env::var_os("DUMPSTER_STORAGE").map_or_else(|| PathBuf::from("storage"), PathBuf::from)
+    }).clone()
+}
 
 #[catch(404)]
 fn not_found() -> &'static str {
@@ -29,6 +68,26 @@ fn payload_too_large() -> &'static str {
     "🍆 413"
 }
 
+#[catch(403)]
+fn forbidden() -> &'static str {
+    "🍆 403"
+}
+
+#[catch(503)]
+fn service_unavailable() -> &'static str {
+    "🍆 503"
+}
+
+#[catch(507)]
+fn insufficient_storage() -> &'static str {
+    "🍆 507"
+}
+
+#[catch(412)]
+fn precondition_failed() -> &'static str {
+    "🍆 412"
+}
+
 #[catch(422)]
 fn unprocessable_entity() -> &'static str {
     "🍆 422"
@@ -60,27 +119,25 @@ struct TokensVec {
     lifespans: HashMap<Token, Instant>,
 }
 
-pub struct AppState {
-    users: HashMap<Arc<str>, Arc<User>>,
+#[derive(Default)]
+struct UsersVec {
+    list: HashMap<Arc<str>, Arc<User>>,
     prefix_map: HashMap<Arc<str>, Arc<User>>,
-    tokens: RwLock<TokensVec>,
 }
 
-impl AppState {
-    pub fn new_from_users() -> Self {
-        let users = get_users()
-            .into_iter()
-            .map(|user| {
-                let _ = fs::read_dir(user.get_path_to_user_folder())
-                    .map_err(|_| {
-                        fs::create_dir(user.get_path_to_user_folder())
-                            .expect("failed to create user folder");
-                    });
+impl UsersVec {
+    /// Fails if a user's folder is missing and can't be created.
+    fn from_users(users: Vec<Arc<User>>) -> io::Result<Self> {
+        for user in &users {
+            if fs::read_dir(user.get_path_to_user_folder()).is_err() {
+                fs::create_dir(user.get_path_to_user_folder())?;
+            }
+        }
 
-                Arc::new(user)
-            })
-            .collect::<Vec<Arc<User>>>();
+        Ok(Self::index(users))
+    }
 
+    fn index(users: Vec<Arc<User>>) -> Self {
         let prefix_map = users
             .clone()
             .into_iter()
@@ -93,7 +150,7 @@ impl AppState {
                 hm
             });
 
-        let users = users
+        let list = users
             .clone()
             .into_iter()
             .fold(HashMap::with_capacity(users.len()), |mut hm, user| {
@@ -103,34 +160,343 @@ impl AppState {
             });
 
         Self {
-            users,
+            list,
             prefix_map,
+        }
+    }
+
+    /// Validates the set as it would look with `user` added or replaced,
+    /// so nothing invalid gets persisted.
+    fn check(&self, user: &User) -> Result<(), Vec<String>> {
+        let others = self.list
+            .values()
+            .filter(|x| x.username() != user.username())
+            .map(|x| x.as_ref());
+
+        let problems = validate_users(others.chain(std::iter::once(user)));
+
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    users: Arc<RwLock<UsersVec>>,
+    tokens: Arc<RwLock<TokensVec>>,
+    resets: Arc<RwLock<HashMap<Token, PasswordReset>>>,
+    usage: Arc<RwLock<UsageVec>>,
+    /// Held while a tus upload's parts are touched, per upload id.
+    tus_locks: Arc<KeyedLocks>,
+    /// Held while a blob is inserted and linked, or released, per digest.
+    blob_locks: Arc<KeyedLocks>,
+    signer: Arc<ReceiptSigner>,
+    config: Arc<Config>,
+}
+
+impl AppState {
+    pub fn new_from_users() -> Result<Self, Vec<String>> {
+        let config = Config::load();
+
+        let (users, mut problems) = get_users(&config);
+
+        problems.extend(validate_users(&users));
+
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+
+        fs::create_dir_all(Staged::get_path_to_staging_folder())
+            .map_err(|why| vec![format!("couldn't create staging folder: {}", why)])?;
+
+        let signer = ReceiptSigner::load_or_generate()
+            .map_err(|why| vec![format!("couldn't load receipt signing key: {}", why)])?;
+
+        let users = users.into_iter().map(Arc::new).collect();
+
+        let users = UsersVec::from_users(users)
+            .map_err(|why| vec![format!("couldn't create user folder: {}", why)])?;
+
+        Ok(Self {
+            users: Arc::new(RwLock::new(users)),
             tokens: Default::default(),
+            resets: Default::default(),
+            usage: Default::default(),
+            tus_locks: Default::default(),
+            blob_locks: Default::default(),
+            signer: Arc::new(signer),
+            config: Arc::new(config),
+        })
+    }
+
+    /// Re-reads user files and swaps them in. If any file is invalid the
+    /// running set is kept as-is, so a typo can't lock everyone out.
+    pub async fn reload_users(&self) {
+        let (users, mut errors) = get_users(&self.config);
+
+        errors.extend(validate_users(&users));
+
+        if !errors.is_empty() {
+            for error in &errors {
+                log::error!("user reload: {}", error);
+            }
+
+            log::error!("user reload aborted, keeping {} running users", self.users.read().await.list.len());
+            return;
+        }
+
+        let users = match UsersVec::from_users(users.into_iter().map(Arc::new).collect()) {
+            Ok(users) => users,
+            Err(why) => {
+                log::error!("user reload: couldn't create user folder: {}", why);
+                log::error!("user reload aborted, keeping {} running users", self.users.read().await.list.len());
+                return;
+            }
+        };
+
+        log::info!("reloaded {} users", users.list.len());
+
+        let mut running = self.users.write().await;
+        self.sync_tokens(&users).await;
+        *running = users;
+    }
+
+    /// Adds or replaces a single user in the running set. The running set
+    /// is left alone if the user's folder can't be created.
+    pub async fn put_user(&self, user: User) -> io::Result<Arc<User>> {
+        let mut running = self.users.write().await;
+
+        self.put_user_into(&mut running, user).await
+    }
+
+    /// Like `put_user`, for callers already holding the users lock.
+    async fn put_user_into(&self, running: &mut UsersVec, user: User) -> io::Result<Arc<User>> {
+        let user = Arc::new(user);
+
+        let users = running.list
+            .values()
+            .filter(|x| x.username() != user.username())
+            .cloned()
+            .chain(std::iter::once(user.clone()))
+            .collect();
+
+        let users = UsersVec::from_users(users)?;
+        self.sync_tokens(&users).await;
+        *running = users;
+
+        Ok(user)
+    }
+
+    pub async fn remove_user(&self, username: &str) -> io::Result<()> {
+        let mut running = self.users.write().await;
+
+        let users = running.list
+            .values()
+            .filter(|x| x.username().as_ref() != username)
+            .cloned()
+            .collect();
+
+        let users = UsersVec::from_users(users)?;
+        self.sync_tokens(&users).await;
+        *running = users;
+
+        Ok(())
+    }
+
+    /// Points sessions at the current version of their user and drops
+    /// sessions of users that were removed or disabled.
+    async fn sync_tokens(&self, users: &UsersVec) {
+        let mut tokens = self.tokens.write().await;
+        let TokensVec { list, lifespans } = &mut *tokens;
+
+        list.retain(|token, user| match users.list.get(&user.username()) {
+            Some(current) if current.is_active() => {
+                *user = current.clone();
+                true
+            }
+            _ => {
+                lifespans.remove(token);
+                false
+            }
+        });
+    }
+
+    /// Periodically evicts expired sessions, including sessions of users
+    /// whose account expired since they logged in.
+    async fn sweep_tokens(self) {
+        const SWEEP_INTERVAL: u64 = 60;
+
+        let mut interval = tokio::time::interval(Duration::from_secs(SWEEP_INTERVAL));
+
+        loop {
+            interval.tick().await;
+
+            let now = Instant::now();
+
+            let mut tokens = self.tokens.write().await;
+            let TokensVec { list, lifespans } = &mut *tokens;
+
+            let before = list.len();
+
+            list.retain(|token, user| {
+                let alive = user.is_active() && lifespans.get(token).is_some_and(|x| *x > now);
+
+                if !alive {
+                    lifespans.remove(token);
+                }
+
+                alive
+            });
+
+            if before != list.len() {
+                log::debug!("swept {} sessions", before - list.len());
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    async fn reload_users_on_hangup(self) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup()).expect("failed to listen for SIGHUP");
+
+        while hangup.recv().await.is_some() {
+            log::info!("SIGHUP received, reloading users");
+
+            self.reload_users().await;
         }
     }
 }
 
-#[launch]
-fn rocket() -> _ {
-    env_logger::init();
+fn rocket() -> Result<Rocket<Build>, String> {
+    let state = AppState::new_from_users().map_err(|problems| {
+        format!("invalid user configuration:\n    {}", problems.join("\n    "))
+    })?;
+
+    let tasks = state.clone();
 
-    rocket::build()
-        .manage(AppState::new_from_users())
+    let rocket = rocket::build()
+        .attach(AdHoc::on_liftoff("Background tasks", move |_| Box::pin(async move {
+            tokio::spawn(tasks.clone().sweep_tokens());
+            tokio::spawn(tus::sweep_stale_uploads(tasks.clone()));
+
+            #[cfg(unix)]
+            tokio::spawn(tasks.reload_users_on_hangup());
+        })))
+        .manage(state)
         .mount("/ajax", routes![
             upload::upload,
+            upload::upload_raw,
+            upload::withdraw,
             auth::login,
             files::list,
+            files::versions,
             files::download_file,
-            auth::logout
+            auth::logout,
+            auth::change_password,
+            auth::reset_password,
+            quota::usage,
+            receipt::public_key,
+            receipt::verify,
+            tus::options,
+            tus::create,
+            tus::offset,
+            tus::append,
+            tus::terminate
+        ])
+        .mount("/ajax/admin", routes![
+            admin::list_users,
+            admin::create_user,
+            admin::update_user,
+            admin::set_password,
+            admin::issue_reset,
+            admin::delete_user
         ])
         .register("/", catchers![
             not_found,
             payload_too_large,
+            precondition_failed,
             unprocessable_entity,
             bad_request,
             unauthorized,
+            forbidden,
             internal_server_error,
+            service_unavailable,
+            insufficient_storage,
             too_many_requests
         ])
-        .mount("/", FileServer::from("public"))
+        .mount("/", FileServer::from("public"));
+
+    Ok(rocket)
+}
+
+#[rocket::main]
+async fn main() {
+    env_logger::init();
+
+    let command = cli::parse(std::env::args().skip(1));
+
+    if let Err(usage) = &command {
+        eprintln!("{}", usage);
+        std::process::exit(2);
+    }
+
+    let result = match command.unwrap() {
+        Command::Serve => match rocket() {
+            Ok(rocket) => rocket.launch().await.map_err(|why| why.to_string()),
+            Err(why) => Err(why),
+        },
+        command => cli::run(command),
+    };
+
+    if let Err(why) = result {
+        eprintln!("error: {}", why);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+impl AppState {
+    /// A state holding `users` with a throwaway signing key. Nothing is
+    /// read from `storage` and no user folders are created.
+    pub fn for_tests(config: Config, users: Vec<User>) -> Self {
+        Self {
+            users: Arc::new(RwLock::new(UsersVec::index(users.into_iter().map(Arc::new).collect()))),
+            tokens: Default::default(),
+            resets: Default::default(),
+            usage: Default::default(),
+            tus_locks: Default::default(),
+            blob_locks: Default::default(),
+            signer: Arc::new(ReceiptSigner::from_secret(&[7; 32]).unwrap()),
+            config: Arc::new(config),
+        }
+    }
+
+    /// Opens a session for `username`, returning its bearer token.
+    pub async fn login_for_tests(&self, username: &str) -> String {
+        let user = self.users.read().await.list[username].clone();
+        let token = auth::new_token();
+
+        let mut tokens = self.tokens.write().await;
+
+        tokens.list.insert(token.as_str().into(), user);
+        tokens.lifespans.insert(token.as_str().into(), Instant::now() + std::time::Duration::from_secs(60));
+
+        token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_users_reports_uncreatable_folders() {
+        let user = Arc::new(User::new("no-such-parent/user"));
+
+        assert!(UsersVec::from_users(vec![user]).is_err());
+    }
 }
@@ -0,0 +1,173 @@
+use std::fmt::Write;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+/// What we know about a stored file, kept in a hidden `.<name>.toml`
+/// sidecar next to it so listings and quotas skip it.
+#[derive(Deserialize, Serialize)]
+pub struct FileMeta {
+    pub sha256: String,
+    pub size: u64,
+    pub uploaded_at: DateTime<Utc>,
+
+    /// SHA-256 of the token handed out in the upload receipt.
+    pub delete_token_hash: Option<String>,
+    pub withdraw_until: Option<DateTime<Utc>>,
+
+    /// Filename as the client sent it, before sanitizing.
+    pub original_name: Option<String>,
+    pub stored_name: Option<String>,
+    pub uploader_ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    /// Content type the client declared.
+    pub content_type: Option<String>,
+    /// Content type judging by the contents, served on download.
+    pub sniffed_type: Option<String>,
+    /// clamd's verdict, missing when scanning is off.
+    pub scan_verdict: Option<String>,
+    /// Added by upload hooks.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+impl FileMeta {
+    pub fn get_path_to_sidecar(file: &Path) -> PathBuf {
+        let name = file
+            .file_name()
+            .and_then(|x| x.to_str())
+            .expect("invalid filename");
+
+        file.with_file_name(format!(".{}.toml", name))
+    }
+
+    pub async fn load(file: &Path) -> io::Result<Self> {
+        let data = tokio::fs::read_to_string(Self::get_path_to_sidecar(file)).await?;
+
+        toml::from_str(&data).map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))
+    }
+
+    pub async fn save(&self, file: &Path) -> io::Result<()> {
+        let data = toml::to_string(self)
+            .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))?;
+
+        tokio::fs::write(Self::get_path_to_sidecar(file), data).await
+    }
+
+    pub async fn remove(file: &Path) -> io::Result<()> {
+        tokio::fs::remove_file(Self::get_path_to_sidecar(file)).await
+    }
+
+    /// Whether `token` withdraws this file right now.
+    pub fn allows_withdrawal(&self, token: &str) -> bool {
+        let in_time = self.withdraw_until.is_some_and(|until| Utc::now() <= until);
+        let token_matches = self.delete_token_hash.as_deref() == Some(sha256_hex(token.as_bytes()).as_str());
+
+        in_time && token_matches
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+
+    for byte in bytes {
+        write!(&mut s, "{:02x}", byte).expect("stringification of hash failed");
+    }
+
+    s
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+pub async fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buf).await?;
+
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..read]);
+    }
+
+    Ok(to_hex(&hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(token: &str, withdraw_until: DateTime<Utc>) -> FileMeta {
+        FileMeta {
+            sha256: sha256_hex(b"contents"),
+            size: 8,
+            uploaded_at: Utc::now(),
+            delete_token_hash: Some(sha256_hex(token.as_bytes())),
+            withdraw_until: Some(withdraw_until),
+            original_name: None,
+            stored_name: None,
+            uploader_ip: None,
+            user_agent: None,
+            content_type: None,
+            sniffed_type: None,
+            scan_verdict: None,
+            tags: vec![],
+        }
+    }
+
+    #[test]
+    fn withdraws_with_the_token_in_time() {
+        let meta = meta("token", Utc::now() + chrono::Duration::minutes(1));
+
+        assert!(meta.allows_withdrawal("token"));
+        assert!(!meta.allows_withdrawal("guess"));
+    }
+
+    #[test]
+    fn refuses_withdrawal_after_the_window() {
+        let meta = meta("token", Utc::now() - chrono::Duration::seconds(1));
+
+        assert!(!meta.allows_withdrawal("token"));
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        assert_eq!(to_hex(&[0, 15, 255]), "000fff");
+        assert_eq!(from_hex("000fff"), Some(vec![0, 15, 255]));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+
+    #[rocket::async_test]
+    async fn hashes_files_like_bytes() {
+        let path = std::env::temp_dir().join(format!("dumpster-{}", crate::auth::new_token()));
+
+        tokio::fs::write(&path, b"contents").await.unwrap();
+        let sha256 = sha256_file(&path).await;
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        assert_eq!(sha256.unwrap(), sha256_hex(b"contents"));
+    }
+}
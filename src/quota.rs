@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use rocket::State;
+use rocket::data::ByteUnit;
+use rocket::serde::json::{json, Value};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::AppState;
+use crate::files::{FileScope, UserToken};
+
+/// Storage limits of a scope. Missing limits aren't enforced.
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Debug)]
+#[serde(default)]
+pub struct Quota {
+    max_bytes: Option<ByteUnit>,
+    max_files: Option<u64>,
+}
+
+impl Quota {
+    pub fn max_bytes(&self) -> Option<ByteUnit> {
+        self.max_bytes
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Usage {
+    bytes: u64,
+    files: u64,
+}
+
+impl Usage {
+    async fn scan(path: &Path) -> Self {
+        let mut usage = Usage::default();
+
+        let rdir = tokio::fs::read_dir(path).await;
+
+        if let Err(why) = &rdir {
+            log::warn!("failed to read_dir {:?} for usage: {}", path, why);
+
+            return usage;
+        }
+
+        let mut rdir = rdir.unwrap();
+
+        while let Ok(Some(entry)) = rdir.next_entry().await {
+            if entry.file_name().to_str().is_none_or(|name| name.starts_with('.')) {
+                continue;
+            }
+
+            if let Ok(metadata) = entry.metadata().await {
+                if metadata.is_file() {
+                    usage.bytes += metadata.len();
+                    usage.files += 1;
+                }
+            }
+        }
+
+        usage
+    }
+
+    fn describe(&self, quota: &Quota) -> Value {
+        json!({
+            "bytes": self.bytes,
+            "files": self.files,
+            "maxBytes": quota.max_bytes.map(|x| x.as_u64()),
+            "maxFiles": quota.max_files,
+        })
+    }
+}
+
+/// Usage per scope, keyed by username with `None` for the common scope.
+/// Each scope is scanned once on first use and kept up to date after.
+#[derive(Default)]
+pub struct UsageVec {
+    list: HashMap<Option<Arc<str>>, Usage>,
+}
+
+impl UsageVec {
+    /// Scans `folder` on first use of `key`. The scan runs without holding
+    /// the lock, so uploads to other scopes aren't held up by it.
+    async fn scan_once(usage: &RwLock<Self>, key: &Option<Arc<str>>, folder: &Path) {
+        if usage.read().await.list.contains_key(key) {
+            return;
+        }
+
+        let scanned = Usage::scan(folder).await;
+
+        log::debug!("scanned usage of {:?}: {:?}", folder, scanned);
+
+        // Another request may have scanned meanwhile, its count is as good.
+        usage.write().await.list.entry(key.clone()).or_insert(scanned);
+    }
+
+    async fn get(usage: &RwLock<Self>, key: &Option<Arc<str>>, folder: &Path) -> Usage {
+        Self::scan_once(usage, key, folder).await;
+
+        usage.read().await.list[key]
+    }
+
+    /// Accounts for a new file of `len` bytes, unless it would exceed `quota`.
+    pub async fn reserve(usage: &RwLock<Self>, key: &Option<Arc<str>>, folder: &Path, quota: &Quota, len: u64) -> Result<(), Value> {
+        Self::scan_once(usage, key, folder).await;
+
+        let mut usage_vec = usage.write().await;
+        let usage = usage_vec.list.get_mut(key).expect("usage scanned above");
+
+        let too_big = quota.max_bytes.is_some_and(|max| usage.bytes + len > max.as_u64());
+        let too_many = quota.max_files.is_some_and(|max| usage.files + 1 > max);
+
+        if too_big || too_many {
+            return Err(usage.describe(quota));
+        }
+
+        usage.bytes += len;
+        usage.files += 1;
+
+        Ok(())
+    }
+
+    /// Gives back what `reserve` took, e.g. when storing the file failed.
+    pub fn release(&mut self, key: &Option<Arc<str>>, len: u64) {
+        if let Some(usage) = self.list.get_mut(key) {
+            usage.bytes = usage.bytes.saturating_sub(len);
+            usage.files = usage.files.saturating_sub(1);
+        }
+    }
+}
+
+#[get("/usage")]
+pub async fn usage(ut: UserToken, state: &State<AppState>) -> Value {
+    let user_quota = state.config.quota_for(Some(ut.user.as_ref()));
+    let user_usage = UsageVec::get(&state.usage, &Some(ut.user.username()), &ut.user.get_path_to_user_folder()).await;
+
+    let common = if state.config.can_access_common(&ut.user) {
+        let common_quota = state.config.quota_for(None);
+        let common_usage = UsageVec::get(&state.usage, &None, &FileScope::get_path_to_common_folder()).await;
+
+        Some(common_usage.describe(&common_quota))
+    } else {
+        None
+    };
+
+    json!({
+        "user": user_usage.describe(&user_quota),
+        "common": common,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rocket::async_test]
+    async fn reserves_until_the_quota_is_full() {
+        let usage = RwLock::new(UsageVec::default());
+        let quota = Quota {
+            max_bytes: Some(ByteUnit::from(100)),
+            max_files: Some(2),
+        };
+
+        let key = Some(Arc::from("user"));
+        let folder = &crate::get_path_to_storage().join("uploads/user/no-such-user");
+
+        assert!(UsageVec::reserve(&usage, &key, folder, &quota, 60).await.is_ok());
+
+        let exceeded = UsageVec::reserve(&usage, &key, folder, &quota, 60).await.unwrap_err();
+
+        assert_eq!(exceeded["bytes"], 60);
+        assert_eq!(exceeded["maxBytes"], 100);
+
+        usage.write().await.release(&key, 60);
+
+        assert!(UsageVec::reserve(&usage, &key, folder, &quota, 60).await.is_ok());
+        assert!(UsageVec::reserve(&usage, &key, folder, &quota, 10).await.is_ok());
+        assert!(UsageVec::reserve(&usage, &key, folder, &quota, 10).await.is_err());
+    }
+}
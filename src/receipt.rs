@@ -0,0 +1,212 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use rocket::State;
+use rocket::serde::json::{json, Json, Value};
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+use crate::files::FileScope;
+use crate::meta::{from_hex, to_hex};
+
+fn get_path_to_key() -> PathBuf {
+    crate::get_path_to_storage().join("receipt.key")
+}
+
+/// Proof of upload handed to the uploader. The delete token is only known
+/// to them; the server keeps just its hash.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Receipt {
+    pub filename: String,
+    pub scope: FileScope,
+    pub recipient: Option<Arc<str>>,
+    pub size: u64,
+    pub sha256: String,
+    pub uploaded_at: DateTime<Utc>,
+    pub withdraw_until: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delete_token: Option<String>,
+    pub signature: Option<String>,
+}
+
+impl Receipt {
+    /// The signed part of a receipt. The delete token and withdrawal
+    /// window are left out, so uploaders can share receipts safely.
+    fn signed_message(&self) -> String {
+        format!(
+            "dumpster-receipt-v1\n{}\n{}\n{}\n{}\n{}\n{}\n",
+            self.filename,
+            self.sha256,
+            self.size,
+            self.uploaded_at.to_rfc3339_opts(SecondsFormat::Nanos, true),
+            match self.scope {
+                FileScope::Common => "common",
+                FileScope::User => "user",
+            },
+            self.recipient.as_deref().unwrap_or(""),
+        )
+    }
+
+    pub fn verify(&self, public_key: &PublicKey) -> bool {
+        let signature = self.signature
+            .as_deref()
+            .and_then(from_hex)
+            .and_then(|bytes| Signature::try_from(bytes.as_slice()).ok());
+
+        signature.is_some_and(|signature| {
+            public_key.verify(self.signed_message().as_bytes(), &signature).is_ok()
+        })
+    }
+}
+
+pub struct ReceiptSigner {
+    keypair: Keypair,
+}
+
+impl ReceiptSigner {
+    pub fn load() -> io::Result<Self> {
+        Self::from_secret(&fs::read(get_path_to_key())?)
+    }
+
+    pub fn from_secret(secret: &[u8]) -> io::Result<Self> {
+        let secret = SecretKey::from_bytes(secret)
+            .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))?;
+        let public = PublicKey::from(&secret);
+
+        Ok(Self {
+            keypair: Keypair { secret, public },
+        })
+    }
+
+    /// Loads the server's signing key, generating one on first start.
+    pub fn load_or_generate() -> io::Result<Self> {
+        Self::load_or_generate_at(&get_path_to_key())
+    }
+
+    fn load_or_generate_at(path: &Path) -> io::Result<Self> {
+        use std::io::Write;
+
+        use rand::RngCore;
+
+        let mut options = fs::OpenOptions::new();
+
+        // Never replaces a key, even one that showed up since we looked.
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        match options.open(path) {
+            Ok(mut file) => {
+                let mut secret = [0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+
+                file.write_all(&secret)?;
+                file.sync_all()?;
+
+                log::info!("generated receipt signing key {:?}", path);
+            }
+            Err(why) if why.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(why) => return Err(why),
+        }
+
+        Self::from_secret(&fs::read(path)?)
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.keypair.public
+    }
+
+    pub fn sign(&self, receipt: &mut Receipt) {
+        let signature = self.keypair.sign(receipt.signed_message().as_bytes());
+
+        receipt.signature = Some(to_hex(&signature.to_bytes()));
+    }
+}
+
+#[get("/receipt/key")]
+pub fn public_key(state: &State<AppState>) -> Value {
+    json!({
+        "algorithm": "ed25519",
+        "publicKey": to_hex(state.signer.public_key().as_bytes()),
+    })
+}
+
+#[post("/receipt/verify", data = "<receipt>")]
+pub fn verify(receipt: Json<Receipt>, state: &State<AppState>) -> Value {
+    json!({
+        "valid": receipt.verify(state.signer.public_key())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::new_token;
+
+    use super::*;
+
+    fn receipt() -> Receipt {
+        Receipt {
+            filename: "1650000000000-report.pdf".to_string(),
+            scope: FileScope::User,
+            recipient: Some("user".into()),
+            size: 42,
+            sha256: "ab".repeat(32),
+            uploaded_at: Utc::now(),
+            withdraw_until: Utc::now(),
+            delete_token: Some("secret".to_string()),
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn generates_private_key_once() {
+        let path = std::env::temp_dir().join(format!("dumpster-{}.key", new_token()));
+
+        let generated = ReceiptSigner::load_or_generate_at(&path).unwrap();
+        let loaded = ReceiptSigner::load_or_generate_at(&path).unwrap();
+
+        assert_eq!(generated.public_key(), loaded.public_key());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn signatures_cover_the_upload() {
+        let signer = ReceiptSigner::from_secret(&[7; 32]).unwrap();
+        let mut receipt = receipt();
+
+        signer.sign(&mut receipt);
+
+        assert!(receipt.verify(signer.public_key()));
+
+        // Leaving out the delete token keeps shared receipts valid.
+        receipt.delete_token = None;
+        assert!(receipt.verify(signer.public_key()));
+
+        receipt.size += 1;
+        assert!(!receipt.verify(signer.public_key()));
+    }
+
+    #[test]
+    fn other_keys_dont_verify() {
+        let mut receipt = receipt();
+
+        ReceiptSigner::from_secret(&[7; 32]).unwrap().sign(&mut receipt);
+
+        assert!(!receipt.verify(ReceiptSigner::from_secret(&[8; 32]).unwrap().public_key()));
+    }
+}
@@ -0,0 +1,290 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// Where clamd listens, as `unix:<path>` or `tcp:<host>:<port>`. Uploads
+/// aren't scanned without it.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct ScanConfig {
+    clamd: Option<String>,
+    /// Seconds a single scan may take.
+    timeout: u64,
+    /// Accept uploads when clamd can't be asked instead of refusing them.
+    fail_open: bool,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self {
+            clamd: None,
+            timeout: 30,
+            fail_open: false,
+        }
+    }
+}
+
+impl ScanConfig {
+    pub fn fail_open(&self) -> bool {
+        self.fail_open
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Verdict {
+    Clean,
+    Infected(String),
+    /// Scanning failed and the upload was let through anyway.
+    Unscanned(String),
+}
+
+impl Verdict {
+    /// How the verdict is recorded in file metadata.
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Clean => "clean".to_string(),
+            Self::Infected(signature) => format!("infected: {}", signature),
+            Self::Unscanned(why) => format!("unscanned: {}", why),
+        }
+    }
+}
+
+pub fn get_path_to_quarantine_folder() -> PathBuf {
+    let mut path = crate::get_path_to_storage();
+
+    path.push("quarantine");
+
+    path
+}
+
+/// Streams `path` to clamd with `INSTREAM`. `None` when scanning is off.
+pub async fn scan(config: &ScanConfig, path: &Path) -> io::Result<Option<Verdict>> {
+    let clamd = match &config.clamd {
+        Some(clamd) => clamd,
+        None => return Ok(None),
+    };
+
+    let scanning = async {
+        let file = tokio::fs::File::open(path).await?;
+
+        if let Some(address) = clamd.strip_prefix("tcp:") {
+            return instream(tokio::net::TcpStream::connect(address).await?, file).await;
+        }
+
+        #[cfg(unix)]
+        if let Some(address) = clamd.strip_prefix("unix:") {
+            return instream(tokio::net::UnixStream::connect(address).await?, file).await;
+        }
+
+        Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid clamd address '{}'", clamd)))
+    };
+
+    match tokio::time::timeout(Duration::from_secs(config.timeout), scanning).await {
+        Ok(verdict) => verdict.map(Some),
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "clamd scan timed out")),
+    }
+}
+
+/// Sends `file` as length prefixed chunks ended by an empty one, then reads
+/// the single reply, e.g. `stream: OK` or `stream: Eicar-Signature FOUND`.
+async fn instream(mut stream: impl AsyncRead + AsyncWrite + Unpin, mut file: tokio::fs::File) -> io::Result<Verdict> {
+    stream.write_all(b"zINSTREAM\0").await?;
+
+    let mut buf = vec![0u8; CHUNK_BYTES];
+
+    loop {
+        let read = file.read(&mut buf).await?;
+
+        stream.write_all(&(read as u32).to_be_bytes()).await?;
+
+        if read == 0 {
+            break;
+        }
+
+        stream.write_all(&buf[..read]).await?;
+    }
+
+    stream.flush().await?;
+
+    let mut reply = String::new();
+
+    stream.read_to_string(&mut reply).await?;
+
+    let reply = reply.trim_end_matches(&['\0', '\n'][..]);
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+
+    if result == "OK" {
+        return Ok(Verdict::Clean);
+    }
+
+    if let Some(signature) = result.strip_suffix(" FOUND") {
+        return Ok(Verdict::Infected(signature.to_string()));
+    }
+
+    Err(io::Error::other(format!("clamd replied '{}'", reply)))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use crate::AppState;
+    use crate::auth::new_token;
+    use crate::config::Config;
+    use crate::meta::FileMeta;
+    use crate::upload::{self, UploadError};
+    use crate::upload::testing::{stage, unquarantine, ScratchUser};
+
+    use super::*;
+
+    /// Part of the EICAR test file, the stub flags anything containing it.
+    const EICAR_MARKER: &[u8] = b"EICAR-STANDARD-ANTIVIRUS-TEST-FILE";
+
+    /// A clamd answering `INSTREAM` like the real one, returning its address.
+    async fn stub_clamd() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    let mut command = [0u8; 10];
+                    stream.read_exact(&mut command).await.unwrap();
+                    assert_eq!(&command, b"zINSTREAM\0");
+
+                    let mut data = vec![];
+
+                    loop {
+                        let len = stream.read_u32().await.unwrap();
+
+                        if len == 0 {
+                            break;
+                        }
+
+                        let mut chunk = vec![0u8; len as usize];
+                        stream.read_exact(&mut chunk).await.unwrap();
+                        data.extend(chunk);
+                    }
+
+                    let reply = if data.windows(EICAR_MARKER.len()).any(|x| x == EICAR_MARKER) {
+                        "stream: Eicar-Test-Signature FOUND\0"
+                    } else {
+                        "stream: OK\0"
+                    };
+
+                    stream.write_all(reply.as_bytes()).await.unwrap();
+                });
+            }
+        });
+
+        format!("tcp:{}", address)
+    }
+
+    /// An address nothing listens on.
+    async fn unreachable_clamd() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        format!("tcp:{}", listener.local_addr().unwrap())
+    }
+
+    fn config(clamd: &str, fail_open: bool) -> Config {
+        toml::from_str(&format!("[scan]\nclamd = \"{}\"\nfail_open = {}", clamd, fail_open)).unwrap()
+    }
+
+    fn infected_contents() -> Vec<u8> {
+        [b"some report ".as_ref(), EICAR_MARKER].concat()
+    }
+
+    #[rocket::async_test]
+    async fn speaks_instream() {
+        let config = config(&stub_clamd().await, false);
+        let path = std::env::temp_dir().join(new_token());
+
+        tokio::fs::write(&path, b"some report").await.unwrap();
+        assert!(matches!(scan(config.scan(), &path).await, Ok(Some(Verdict::Clean))));
+
+        tokio::fs::write(&path, infected_contents()).await.unwrap();
+        assert!(matches!(scan(config.scan(), &path).await, Ok(Some(Verdict::Infected(x))) if x == "Eicar-Test-Signature"));
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn skips_scanning_without_clamd() {
+        let path = std::env::temp_dir().join(new_token());
+
+        assert!(matches!(scan(&ScanConfig::default(), &path).await, Ok(None)));
+    }
+
+    #[rocket::async_test]
+    async fn stores_clean_uploads_with_their_verdict() {
+        let scratch = ScratchUser::new().await;
+        let state = AppState::for_tests(config(&stub_clamd().await, false), vec![scratch.user.clone()]);
+
+        let receipt = upload::store(&state, stage(&scratch.user, "report.txt", b"some report").await).await.unwrap();
+
+        let path = scratch.user.get_path_to_user_file(&receipt.filename);
+
+        assert_eq!(FileMeta::load(&path).await.unwrap().scan_verdict.as_deref(), Some("clean"));
+
+        scratch.cleanup().await;
+    }
+
+    #[rocket::async_test]
+    async fn quarantines_infected_uploads() {
+        let scratch = ScratchUser::new().await;
+        let state = AppState::for_tests(config(&stub_clamd().await, false), vec![scratch.user.clone()]);
+
+        let raw_name = format!("{}.txt", &new_token()[..12]);
+        let staged = stage(&scratch.user, &raw_name, &infected_contents()).await;
+        let staged_path = staged.path.clone();
+
+        let result = upload::store(&state, staged).await;
+
+        assert!(matches!(&result, Err(UploadError::Infected(x)) if x == "Eicar-Test-Signature"));
+        assert_eq!(result.err().unwrap().body()["signature"], "Eicar-Test-Signature");
+        assert!(scratch.stored().await.is_empty());
+        assert!(!staged_path.exists());
+
+        let file_meta = unquarantine(&raw_name).await.expect("upload not quarantined");
+
+        assert_eq!(file_meta.scan_verdict.as_deref(), Some("infected: Eicar-Test-Signature"));
+
+        scratch.cleanup().await;
+    }
+
+    #[rocket::async_test]
+    async fn refuses_uploads_when_clamd_is_unreachable() {
+        let scratch = ScratchUser::new().await;
+        let state = AppState::for_tests(config(&unreachable_clamd().await, false), vec![scratch.user.clone()]);
+
+        let result = upload::store(&state, stage(&scratch.user, "report.txt", b"some report").await).await;
+
+        assert!(matches!(result, Err(UploadError::ScanFailed)));
+        assert!(scratch.stored().await.is_empty());
+
+        scratch.cleanup().await;
+    }
+
+    #[rocket::async_test]
+    async fn fails_open_when_configured() {
+        let scratch = ScratchUser::new().await;
+        let state = AppState::for_tests(config(&unreachable_clamd().await, true), vec![scratch.user.clone()]);
+
+        let receipt = upload::store(&state, stage(&scratch.user, "report.txt", b"some report").await).await.unwrap();
+
+        let path = scratch.user.get_path_to_user_file(&receipt.filename);
+        let verdict = FileMeta::load(&path).await.unwrap().scan_verdict.unwrap();
+
+        assert!(verdict.starts_with("unscanned: "), "{}", verdict);
+
+        scratch.cleanup().await;
+    }
+}
@@ -0,0 +1,132 @@
+use std::io;
+use std::path::Path;
+
+use tokio::io::AsyncReadExt;
+
+/// How much of a file is looked at. Markup past this point goes unnoticed,
+/// which is why downloads are served with `nosniff` as well.
+const SNIFF_BYTES: usize = 64 * 1024;
+
+/// Markers of HTML anywhere in a text file. `infer` only recognises HTML
+/// at the very start, browsers are less picky.
+const HTML_MARKERS: &[&str] = &["<!doctype html", "<html", "<head", "<body", "<script", "<iframe"];
+
+/// What a file really is, judging by its contents.
+#[derive(Debug, Clone)]
+pub struct Sniffed {
+    pub mime_type: String,
+    /// HTML or SVG that can run scripts when opened in a browser.
+    pub scripted: bool,
+}
+
+impl Sniffed {
+    fn inert(mime_type: &str) -> Self {
+        Self {
+            mime_type: mime_type.to_string(),
+            scripted: false,
+        }
+    }
+}
+
+pub async fn sniff(path: &Path) -> io::Result<Sniffed> {
+    let file = tokio::fs::File::open(path).await?;
+    let mut buf = Vec::with_capacity(SNIFF_BYTES);
+
+    file.take(SNIFF_BYTES as u64).read_to_end(&mut buf).await?;
+
+    Ok(sniff_bytes(&buf))
+}
+
+fn sniff_bytes(buf: &[u8]) -> Sniffed {
+    let inferred = infer::get(buf).map(|x| x.mime_type());
+
+    // Binary formats are trusted as detected, only text needs a closer look.
+    let text = match (inferred, std::str::from_utf8(buf)) {
+        (Some(mime_type), _) if !is_markup(mime_type) => return Sniffed::inert(mime_type),
+        (_, Ok(text)) => text.to_lowercase(),
+        // A multibyte character cut off at the end of the buffer.
+        (_, Err(why)) if why.error_len().is_none() => String::from_utf8_lossy(buf).to_lowercase(),
+        (_, Err(_)) => return Sniffed::inert(inferred.unwrap_or("application/octet-stream")),
+    };
+
+    let mime_type = if text.contains("<svg") {
+        "image/svg+xml"
+    } else if HTML_MARKERS.iter().any(|marker| text.contains(marker)) {
+        "text/html"
+    } else {
+        return Sniffed::inert(inferred.unwrap_or("text/plain"));
+    };
+
+    Sniffed {
+        mime_type: mime_type.to_string(),
+        scripted: has_scripts(&text),
+    }
+}
+
+fn is_markup(mime_type: &str) -> bool {
+    matches!(mime_type, "text/html" | "text/xml")
+}
+
+/// `text` must be lowercase already.
+fn has_scripts(text: &str) -> bool {
+    text.contains("<script") || text.contains("javascript:") || has_event_handler(text)
+}
+
+/// Looks for attributes like ` onload=` or ` onclick =`.
+fn has_event_handler(text: &str) -> bool {
+    text.match_indices("on").any(|(i, _)| {
+        let before = text[..i].chars().next_back();
+
+        if !before.is_some_and(|c| c.is_whitespace() || c == '/' || c == '"' || c == '\'') {
+            return false;
+        }
+
+        let rest = text[i + 2..].trim_start_matches(|c: char| c.is_ascii_alphabetic());
+
+        rest.len() < text.len() - i - 2 && rest.trim_start().starts_with('=')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trusts_binary_formats() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR<script>";
+        let sniffed = sniff_bytes(png);
+
+        assert_eq!(sniffed.mime_type, "image/png");
+        assert!(!sniffed.scripted);
+    }
+
+    #[test]
+    fn finds_markup_anywhere_in_text() {
+        let sniffed = sniff_bytes(b"notes\n\n<HTML><body>hi</body></HTML>");
+
+        assert_eq!(sniffed.mime_type, "text/html");
+        assert!(!sniffed.scripted);
+
+        assert_eq!(sniff_bytes(b"just notes").mime_type, "text/plain");
+    }
+
+    #[test]
+    fn flags_scripted_markup() {
+        assert!(sniff_bytes(b"<svg onload = \"alert(1)\"></svg>").scripted);
+        assert!(sniff_bytes(b"<html><script>alert(1)</script>").scripted);
+        assert!(sniff_bytes(b"<html><a href=\"JavaScript:alert(1)\">").scripted);
+
+        let svg = sniff_bytes(b"<svg><text>once upon a time</text></svg>");
+
+        assert_eq!(svg.mime_type, "image/svg+xml");
+        assert!(!svg.scripted);
+    }
+
+    #[test]
+    fn tolerates_characters_cut_off_at_the_end() {
+        let mut buf = b"<html>\xc3\xbc".to_vec();
+        buf.pop();
+
+        assert_eq!(sniff_bytes(&buf).mime_type, "text/html");
+    }
+}
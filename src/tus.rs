@@ -0,0 +1,583 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use rocket::{Request, Response, State};
+use rocket::data::{ByteUnit, Data, Limits};
+use rocket::http::{Header, HeaderMap, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder};
+use rocket::serde::json::{json, Value};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::AppState;
+use crate::auth::new_token;
+use crate::upload::{self, Staged, Uploader, UploadError};
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,expiration,termination";
+
+/// An upload created through tus, kept as `<id>.toml` next to its
+/// partial file in the staging folder until the last byte arrives.
+#[derive(Deserialize, Serialize)]
+struct PendingUpload {
+    length: u64,
+    filename: String,
+    filetype: Option<String>,
+    recipient: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl PendingUpload {
+    fn get_path_to_file(id: &str) -> PathBuf {
+        let mut path = Staged::get_path_to_staging_folder();
+
+        path.push(id);
+
+        path
+    }
+
+    fn get_path_to_info(id: &str) -> PathBuf {
+        Self::get_path_to_file(id).with_extension("toml")
+    }
+
+    async fn load(id: &str) -> io::Result<Self> {
+        let data = tokio::fs::read_to_string(Self::get_path_to_info(id)).await?;
+
+        toml::from_str(&data).map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))
+    }
+
+    async fn save(&self, id: &str) -> io::Result<()> {
+        let data = toml::to_string(self)
+            .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))?;
+
+        tokio::fs::write(Self::get_path_to_info(id), data).await
+    }
+
+    /// When the upload gets swept if it isn't finished by then.
+    fn expires_at(&self, state: &AppState) -> DateTime<Utc> {
+        self.created_at + state.config.staging_expiry()
+    }
+
+    async fn offset(id: &str) -> io::Result<u64> {
+        Ok(tokio::fs::metadata(Self::get_path_to_file(id)).await?.len())
+    }
+
+    async fn remove(id: &str) {
+        let _ = tokio::fs::remove_file(Self::get_path_to_file(id)).await;
+        let _ = tokio::fs::remove_file(Self::get_path_to_info(id)).await;
+    }
+}
+
+/// Requests speaking our version of tus; anything else gets a 412.
+pub struct Tus<'r> {
+    headers: &'r HeaderMap<'r>,
+    max_size: ByteUnit,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Tus<'r> {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if request.headers().get_one("Tus-Resumable") != Some(TUS_VERSION) {
+            return Outcome::Failure((Status::PreconditionFailed, "unsupported tus version"));
+        }
+
+        Outcome::Success(Tus {
+            headers: request.headers(),
+            max_size: max_size(request.limits()),
+        })
+    }
+}
+
+impl<'r> Tus<'r> {
+    fn number(&self, name: &str) -> Option<u64> {
+        self.headers.get_one(name).and_then(|x| x.parse().ok())
+    }
+
+    /// `Upload-Metadata` pairs, values base64 decoded.
+    fn metadata(&self) -> HashMap<&'r str, String> {
+        self.headers
+            .get_one("Upload-Metadata")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|pair| {
+                let mut pair = pair.trim().splitn(2, ' ');
+                let key = pair.next().filter(|x| !x.is_empty())?;
+                let value = base64::decode(pair.next().unwrap_or_default()).ok()?;
+
+                Some((key, String::from_utf8(value).ok()?))
+            })
+            .collect()
+    }
+}
+
+fn max_size(limits: &Limits) -> ByteUnit {
+    limits.get("file").unwrap_or(Limits::FILE)
+}
+
+/// `Upload-Expires` wants an HTTP date.
+fn http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+pub struct TusResponse {
+    status: Status,
+    headers: Vec<Header<'static>>,
+    body: Option<Value>,
+}
+
+impl TusResponse {
+    fn new(status: Status) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: None,
+        }
+    }
+
+    fn error(status: Status, message: &str) -> Self {
+        Self::new(status).body(json!({
+            "error": message
+        }))
+    }
+
+    fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push(Header::new(name, value.to_string()));
+        self
+    }
+
+    fn body(mut self, body: Value) -> Self {
+        self.body = Some(body);
+        self
+    }
+}
+
+impl From<UploadError> for TusResponse {
+    fn from(why: UploadError) -> Self {
+        Self::new(why.status()).body(why.body())
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TusResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = match self.body {
+            Some(body) => body.respond_to(request)?,
+            None => Response::new(),
+        };
+
+        response.set_status(self.status);
+        response.set_header(Header::new("Tus-Resumable", TUS_VERSION));
+
+        for header in self.headers {
+            response.set_header(header);
+        }
+
+        Ok(response)
+    }
+}
+
+/// Upload ids are our own hex tokens, anything else can't name a staged file.
+fn check_id(id: &str) -> Result<(), TusResponse> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(TusResponse::error(Status::NotFound, "unknown upload"));
+    }
+
+    Ok(())
+}
+
+/// Uploads past their expiry are gone even before the sweep gets to them.
+async fn load(state: &AppState, id: &str) -> Result<(PendingUpload, u64), TusResponse> {
+    check_id(id)?;
+
+    let pending = PendingUpload::load(id).await;
+    let offset = PendingUpload::offset(id).await;
+
+    match (pending, offset) {
+        (Ok(pending), _) if pending.expires_at(state) < Utc::now() => Err(TusResponse::error(Status::Gone, "upload expired")),
+        (Ok(pending), Ok(offset)) => Ok((pending, offset)),
+        _ => Err(TusResponse::error(Status::NotFound, "unknown upload")),
+    }
+}
+
+#[options("/tus")]
+pub fn options(limits: &Limits) -> TusResponse {
+    TusResponse::new(Status::NoContent)
+        .header("Tus-Version", TUS_VERSION)
+        .header("Tus-Extension", TUS_EXTENSIONS)
+        .header("Tus-Max-Size", max_size(limits).as_u64())
+}
+
+#[post("/tus")]
+pub async fn create(tus: Tus<'_>, state: &State<AppState>) -> Result<TusResponse, TusResponse> {
+    let length = tus.number("Upload-Length")
+        .ok_or_else(|| TusResponse::error(Status::BadRequest, "missing or invalid Upload-Length"))?;
+
+    if length > tus.max_size.as_u64() {
+        return Err(UploadError::FileTooLarge(tus.max_size.as_u64()).into());
+    }
+
+    let mut metadata = tus.metadata();
+
+    let filename = metadata.remove("filename")
+        .ok_or(UploadError::InvalidFilename("filename empty or invalid"))?;
+
+    let recipient = metadata.remove("recipient");
+
+    upload::check_filename(&filename)?;
+    upload::route(state, &filename, recipient.as_deref()).await?;
+
+    let id = new_token();
+
+    let pending = PendingUpload {
+        length,
+        filename,
+        filetype: metadata.remove("filetype"),
+        recipient,
+        created_at: Utc::now(),
+    };
+
+    let created = match tokio::fs::File::create(PendingUpload::get_path_to_file(&id)).await {
+        Ok(_) => pending.save(&id).await,
+        Err(why) => Err(why),
+    };
+
+    if let Err(why) = created {
+        log::warn!("failed to create tus upload {}: {}", id, why);
+
+        PendingUpload::remove(&id).await;
+
+        return Err(UploadError::from(why).into());
+    }
+
+    log::debug!("created tus upload {} for {:?} ({} bytes)", id, pending.filename, length);
+
+    Ok(TusResponse::new(Status::Created)
+        .header("Location", format!("/ajax/tus/{}", id))
+        .header("Upload-Expires", http_date(pending.expires_at(state))))
+}
+
+#[head("/tus/<id>")]
+pub async fn offset(id: &str, _tus: Tus<'_>, state: &State<AppState>) -> Result<TusResponse, TusResponse> {
+    let (pending, offset) = load(state, id).await?;
+
+    Ok(TusResponse::new(Status::Ok)
+        .header("Upload-Offset", offset)
+        .header("Upload-Length", pending.length)
+        .header("Upload-Expires", http_date(pending.expires_at(state)))
+        .header("Cache-Control", "no-store"))
+}
+
+#[patch("/tus/<id>", data = "<data>")]
+pub async fn append(id: &str, tus: Tus<'_>, data: Data<'_>, uploader: Uploader, state: &State<AppState>) -> Result<TusResponse, TusResponse> {
+    if tus.headers.get_one("Content-Type") != Some("application/offset+octet-stream") {
+        return Err(TusResponse::error(Status::UnsupportedMediaType, "expected application/offset+octet-stream"));
+    }
+
+    check_id(id)?;
+
+    // Concurrent PATCHes would both append at the same offset.
+    let _guard = state.tus_locks.lock(id).await;
+
+    let (pending, offset) = load(state, id).await?;
+
+    if tus.number("Upload-Offset") != Some(offset) {
+        return Err(TusResponse::error(Status::Conflict, "Upload-Offset mismatch")
+            .header("Upload-Offset", offset));
+    }
+
+    let path = PendingUpload::get_path_to_file(id);
+
+    let file = tokio::fs::OpenOptions::new().append(true).open(&path).await;
+
+    if let Err(why) = file {
+        log::warn!("failed to open tus upload {}: {}", id, why);
+
+        return Err(UploadError::from(why).into());
+    }
+
+    let mut file = file.unwrap();
+
+    // Whatever made it to disk counts, even if the client went away midway.
+    let streamed = data.open(ByteUnit::from(pending.length - offset)).stream_to(&mut file).await;
+    let flushed = file.flush().await;
+
+    if let Err(why) = streamed.map(|_| ()).and(flushed) {
+        log::info!("tus upload {} interrupted: {}", id, why);
+    }
+
+    let offset = PendingUpload::offset(id).await.map_err(UploadError::from)?;
+
+    if offset < pending.length {
+        return Ok(TusResponse::new(Status::NoContent)
+            .header("Upload-Offset", offset)
+            .header("Upload-Expires", http_date(pending.expires_at(state))));
+    }
+
+    let _ = tokio::fs::remove_file(PendingUpload::get_path_to_info(id)).await;
+
+    let staged = Staged {
+        path,
+        raw_name: pending.filename,
+        content_type: pending.filetype,
+        len: offset,
+        recipient: pending.recipient,
+        uploader,
+    };
+
+    let receipt = upload::store(state, staged).await?;
+
+    log::debug!("finished tus upload {} as {:?}", id, receipt.filename);
+
+    // tus wants a 204 here, so the receipt travels in a header.
+    let receipt = base64::encode(json!(receipt).to_string());
+
+    Ok(TusResponse::new(Status::NoContent)
+        .header("Upload-Offset", offset)
+        .header("Upload-Receipt", receipt))
+}
+
+#[delete("/tus/<id>")]
+pub async fn terminate(id: &str, _tus: Tus<'_>, state: &State<AppState>) -> Result<TusResponse, TusResponse> {
+    check_id(id)?;
+
+    let _guard = state.tus_locks.lock(id).await;
+
+    load(state, id).await?;
+
+    PendingUpload::remove(id).await;
+
+    log::debug!("terminated tus upload {}", id);
+
+    Ok(TusResponse::new(Status::NoContent))
+}
+
+/// Periodically removes uploads that weren't finished in time, along with
+/// staged files that outlived their request.
+pub async fn sweep_stale_uploads(state: AppState) {
+    const SWEEP_INTERVAL: u64 = 10 * 60;
+
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(SWEEP_INTERVAL));
+
+    loop {
+        interval.tick().await;
+
+        match sweep(&state).await {
+            Ok(0) => {}
+            Ok(swept) => log::info!("swept {} stale staged uploads", swept),
+            Err(why) => log::warn!("failed to sweep staging folder: {}", why),
+        }
+    }
+}
+
+async fn sweep(state: &AppState) -> io::Result<usize> {
+    let expiry = state.config.staging_expiry();
+    let now = Utc::now();
+
+    let mut swept = 0;
+    let mut rdir = tokio::fs::read_dir(Staged::get_path_to_staging_folder()).await?;
+
+    while let Some(entry) = rdir.next_entry().await? {
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+
+        if let Some(id) = name.strip_suffix(".toml") {
+            if check_id(id).is_err() {
+                continue;
+            }
+
+            let _guard = state.tus_locks.lock(id).await;
+
+            // Unreadable info can't ever be finished either.
+            let stale = PendingUpload::load(id).await.map_or(true, |pending| pending.created_at + expiry < now);
+
+            if stale {
+                log::debug!("sweeping stale tus upload {}", id);
+
+                PendingUpload::remove(id).await;
+                swept += 1;
+            }
+
+            continue;
+        }
+
+        // Plain staged files belong to a running request and tus data to
+        // its info file, unless they've been lying around for too long.
+        if check_id(name).is_err() || PendingUpload::get_path_to_info(name).exists() {
+            continue;
+        }
+
+        // Files may be stored and gone by now, which is fine.
+        let modified = match entry.metadata().await.and_then(|x| x.modified()) {
+            Ok(modified) => DateTime::<Utc>::from(modified),
+            Err(_) => continue,
+        };
+
+        if modified + expiry < now && tokio::fs::remove_file(entry.path()).await.is_ok() {
+            log::debug!("swept stale staged file {:?}", name);
+
+            swept += 1;
+        }
+    }
+
+    Ok(swept)
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::asynchronous::{Client, LocalResponse};
+
+    use crate::config::Config;
+    use crate::upload::testing::ScratchUser;
+
+    use super::*;
+
+    async fn client(scratch: &ScratchUser) -> Client {
+        let state = AppState::for_tests(Config::default(), vec![scratch.user.clone()]);
+
+        let rocket = rocket::build()
+            .manage(state)
+            .mount("/", routes![options, create, offset, append, terminate]);
+
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    fn header<'a>(response: &'a LocalResponse<'_>, name: &str) -> Option<&'a str> {
+        response.headers().get_one(name)
+    }
+
+    async fn create_upload(client: &Client, scratch: &ScratchUser, length: u64) -> String {
+        let metadata = format!(
+            "filename {},recipient {}",
+            base64::encode("report.txt"),
+            base64::encode(scratch.user.username().as_bytes()),
+        );
+
+        let response = client.post("/tus")
+            .header(Header::new("Tus-Resumable", TUS_VERSION))
+            .header(Header::new("Upload-Length", length.to_string()))
+            .header(Header::new("Upload-Metadata", metadata))
+            .dispatch().await;
+
+        assert_eq!(response.status(), Status::Created);
+        assert!(header(&response, "Upload-Expires").unwrap().ends_with(" GMT"));
+
+        header(&response, "Location").unwrap().rsplit('/').next().unwrap().to_string()
+    }
+
+    async fn patch<'c>(client: &'c Client, id: &str, offset: u64, body: &str) -> LocalResponse<'c> {
+        client.patch(format!("/tus/{}", id))
+            .header(Header::new("Tus-Resumable", TUS_VERSION))
+            .header(Header::new("Content-Type", "application/offset+octet-stream"))
+            .header(Header::new("Upload-Offset", offset.to_string()))
+            .body(body)
+            .dispatch().await
+    }
+
+    #[rocket::async_test]
+    async fn advertises_extensions() {
+        let scratch = ScratchUser::new().await;
+        let client = client(&scratch).await;
+
+        let response = client.options("/tus").dispatch().await;
+
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(header(&response, "Tus-Extension"), Some(TUS_EXTENSIONS));
+        assert_eq!(header(&response, "Tus-Max-Size"), Some(Limits::FILE.as_u64().to_string().as_str()));
+
+        scratch.cleanup().await;
+    }
+
+    #[rocket::async_test]
+    async fn resumes_and_finishes_with_no_content() {
+        let scratch = ScratchUser::new().await;
+        let client = client(&scratch).await;
+
+        let id = create_upload(&client, &scratch, 11).await;
+
+        let response = patch(&client, &id, 0, "some ").await;
+
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(header(&response, "Upload-Offset"), Some("5"));
+
+        let response = patch(&client, &id, 0, "again").await;
+
+        assert_eq!(response.status(), Status::Conflict);
+        assert_eq!(header(&response, "Upload-Offset"), Some("5"));
+
+        let response = patch(&client, &id, 5, "report").await;
+
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(header(&response, "Upload-Offset"), Some("11"));
+
+        let receipt = base64::decode(header(&response, "Upload-Receipt").unwrap()).unwrap();
+        let receipt = rocket::serde::json::from_slice::<Value>(&receipt).unwrap();
+
+        assert_eq!(receipt["size"], 11);
+        assert_eq!(scratch.stored().await, [receipt["filename"].as_str().unwrap()]);
+        assert!(response.into_bytes().await.is_none_or(|body| body.is_empty()));
+
+        assert!(!PendingUpload::get_path_to_file(&id).exists());
+
+        scratch.cleanup().await;
+    }
+
+    #[rocket::async_test]
+    async fn refuses_expired_uploads() {
+        let scratch = ScratchUser::new().await;
+        let client = client(&scratch).await;
+        let state = client.rocket().state::<AppState>().unwrap();
+
+        let id = create_upload(&client, &scratch, 11).await;
+
+        let mut pending = PendingUpload::load(&id).await.unwrap();
+        pending.created_at = Utc::now() - state.config.staging_expiry() - chrono::Duration::seconds(1);
+        pending.save(&id).await.unwrap();
+
+        let response = client.head(format!("/tus/{}", id))
+            .header(Header::new("Tus-Resumable", TUS_VERSION))
+            .dispatch().await;
+
+        assert_eq!(response.status(), Status::Gone);
+        assert_eq!(patch(&client, &id, 0, "some report").await.status(), Status::Gone);
+        assert!(scratch.stored().await.is_empty());
+
+        PendingUpload::remove(&id).await;
+        scratch.cleanup().await;
+    }
+
+    #[rocket::async_test]
+    async fn sweeps_expired_uploads() {
+        let scratch = ScratchUser::new().await;
+        let client = client(&scratch).await;
+        let state = client.rocket().state::<AppState>().unwrap();
+
+        let stale = create_upload(&client, &scratch, 11).await;
+        let fresh = create_upload(&client, &scratch, 11).await;
+
+        let mut pending = PendingUpload::load(&stale).await.unwrap();
+        pending.created_at = Utc::now() - state.config.staging_expiry() - chrono::Duration::seconds(1);
+        pending.save(&stale).await.unwrap();
+
+        sweep(state).await.unwrap();
+
+        assert!(!PendingUpload::get_path_to_file(&stale).exists());
+        assert!(!PendingUpload::get_path_to_info(&stale).exists());
+        assert!(PendingUpload::get_path_to_info(&fresh).exists());
+
+        let response = client.delete(format!("/tus/{}", fresh))
+            .header(Header::new("Tus-Resumable", TUS_VERSION))
+            .dispatch().await;
+
+        assert_eq!(response.status(), Status::NoContent);
+        assert!(!PendingUpload::get_path_to_file(&fresh).exists());
+
+        scratch.cleanup().await;
+    }
+}
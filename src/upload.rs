@@ -1,24 +1,168 @@
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::ffi::OsStr;
+use std::io;
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use rocket::State;
+use chrono::Utc;
+use rocket::{Request, State};
+use rocket::data::{Data, Limits};
 use rocket::form::Form;
 use rocket::fs::TempFile;
-use rocket::http::Status;
-use rocket::serde::json::{json, Value};
+use rocket::http::{ContentType, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder};
+use rocket::serde::json::{json, Json, Value};
 
 use crate::AppState;
+use crate::auth::new_token;
+use crate::blob;
+use crate::config::CollisionPolicy;
 use crate::files::FileScope;
-use crate::user::User;
+use crate::hook::Decision;
+use crate::meta::{self, FileMeta};
+use crate::quota::UsageVec;
+use crate::receipt::Receipt;
+use crate::scan::{self, Verdict};
+use crate::sniff::{self, Sniffed};
+use crate::user::{FilePrefix, User};
 
 #[derive(FromForm, Debug)]
 pub struct UploadData<'r> {
-    file: TempFile<'r>,
+    file: Vec<TempFile<'r>>,
+    /// Username to deliver to, bypassing prefix routing.
+    recipient: Option<String>,
 }
 
-fn sanitize_filename<'r>(given_filename: impl AsRef<OsStr>) -> Option<String> {
+#[derive(FromForm, Debug)]
+pub struct WithdrawData<'r> {
+    filename: &'r str,
+    scope: FileScope,
+    recipient: Option<&'r str>,
+    token: &'r str,
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    InvalidFilename(&'static str),
+    UnknownRecipient,
+    FileTooLarge(u64),
+    ExtensionNotAllowed(Vec<Arc<str>>),
+    TypeNotAllowed(Vec<Arc<str>>),
+    ContentNotAllowed(String),
+    Infected(String),
+    ScanFailed,
+    /// Hook name and the reason it gave, if any.
+    RejectedByHook(String, Option<String>),
+    HookFailed(String),
+    QuotaExceeded(Value),
+    DiskFull,
+    PermissionDenied,
+    Collision,
+    Storage,
+}
+
+impl From<io::Error> for UploadError {
+    fn from(why: io::Error) -> Self {
+        #[cfg(unix)]
+        const DISK_FULL: &[i32] = &[28]; // ENOSPC
+        #[cfg(not(unix))]
+        const DISK_FULL: &[i32] = &[39, 112]; // ERROR_HANDLE_DISK_FULL, ERROR_DISK_FULL
+
+        match why.kind() {
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied,
+            io::ErrorKind::AlreadyExists => Self::Collision,
+            _ if why.raw_os_error().is_some_and(|code| DISK_FULL.contains(&code)) => Self::DiskFull,
+            _ => Self::Storage,
+        }
+    }
+}
+
+impl UploadError {
+    pub fn status(&self) -> Status {
+        match self {
+            Self::InvalidFilename(_) => Status::BadRequest,
+            Self::UnknownRecipient => Status::NotFound,
+            Self::FileTooLarge(_) => Status::PayloadTooLarge,
+            Self::ExtensionNotAllowed(_) | Self::TypeNotAllowed(_) | Self::ContentNotAllowed(_) => Status::UnsupportedMediaType,
+            Self::Infected(_) | Self::RejectedByHook(..) => Status::UnprocessableEntity,
+            Self::ScanFailed | Self::HookFailed(_) => Status::ServiceUnavailable,
+            Self::QuotaExceeded(_) | Self::DiskFull => Status::InsufficientStorage,
+            Self::Collision => Status::Conflict,
+            Self::PermissionDenied | Self::Storage => Status::InternalServerError,
+        }
+    }
+
+    pub fn body(&self) -> Value {
+        match self {
+            Self::InvalidFilename(why) => json!({
+                "error": why
+            }),
+            Self::UnknownRecipient => json!({
+                "error": "unknown recipient"
+            }),
+            Self::FileTooLarge(max_size) => json!({
+                "error": "file too large",
+                "maxSize": max_size,
+            }),
+            Self::ExtensionNotAllowed(allowed) => json!({
+                "error": "file extension not allowed",
+                "allowedExtensions": allowed,
+            }),
+            Self::TypeNotAllowed(allowed) => json!({
+                "error": "file type not allowed",
+                "allowedTypes": allowed,
+            }),
+            Self::ContentNotAllowed(detected) => json!({
+                "error": "file content not allowed",
+                "detectedType": detected,
+            }),
+            Self::Infected(signature) => json!({
+                "error": "file failed virus scan",
+                "signature": signature,
+            }),
+            Self::ScanFailed => json!({
+                "error": "virus scan unavailable"
+            }),
+            Self::RejectedByHook(hook, reason) => json!({
+                "error": "file rejected by upload check",
+                "hook": hook,
+                "reason": reason,
+            }),
+            Self::HookFailed(hook) => json!({
+                "error": "upload check unavailable",
+                "hook": hook,
+            }),
+            Self::QuotaExceeded(usage) => json!({
+                "error": "quota exceeded",
+                "usage": usage,
+            }),
+            Self::DiskFull => json!({
+                "error": "server storage full"
+            }),
+            Self::PermissionDenied => json!({
+                "error": "server storage not writable"
+            }),
+            Self::Collision => json!({
+                "error": "file already exists"
+            }),
+            Self::Storage => json!({
+                "error": "failed to store file"
+            }),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for UploadError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        (self.status(), self.body()).respond_to(request)
+    }
+}
+
+fn sanitize_filename(given_filename: impl AsRef<OsStr>) -> Option<String> {
     #[cfg(not(unix))]
         let (bad_char, bad_name) = {
         static BAD_CHARS: &[char] = &[
@@ -63,7 +207,7 @@ fn sanitize_filename<'r>(given_filename: impl AsRef<OsStr>) -> Option<String> {
     let file_name = std::path::Path::new(&given_filename)
         .file_name()
         .and_then(|n| n.to_str())
-        .and_then(|n| n.split(bad_char).filter(|s| !s.is_empty()).next())?;
+        .and_then(|n| n.split(bad_char).find(|s| !s.is_empty()))?;
 
     // At this point, `file_name` can't contain `bad_chars` because of
     // `.split()`, but it can be empty or reserved.
@@ -74,7 +218,7 @@ fn sanitize_filename<'r>(given_filename: impl AsRef<OsStr>) -> Option<String> {
     let extension = std::path::Path::new(&given_filename)
         .extension()
         .and_then(|x| x.to_str())
-        .and_then(|x| x.split(bad_char).filter(|s| !s.is_empty()).next())?;
+        .and_then(|x| x.split(bad_char).find(|s| !s.is_empty()))?;
 
     // At this point, `file_name` can't contain `bad_chars` because of
     // `.split()`, but it can be empty or reserved.
@@ -114,71 +258,1096 @@ fn guess_scope_from_filename(filename: impl AsRef<OsStr>, prefixes: &HashMap<Arc
     )
 }
 
-#[post("/upload", data = "<form>")]
-pub async fn upload(mut form: Form<UploadData<'_>>, state: &State<AppState>) -> Result<(), (Status, Value)> {
-    let file = &mut form.file;
+/// A file received into the staging area but not yet routed anywhere.
+pub struct Staged {
+    pub path: PathBuf,
+    /// Client supplied filename, before sanitizing.
+    pub raw_name: String,
+    pub content_type: Option<String>,
+    pub len: u64,
+    /// Username the uploader picked, if any.
+    pub recipient: Option<String>,
+    pub uploader: Uploader,
+}
 
-    if file.name().is_none() {
-        log::warn!("tried to upload file with invalid or missing filename");
+/// Who sent an upload, as far as the request tells.
+#[derive(Debug, Clone, Default)]
+pub struct Uploader {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
 
-        return Err((Status::BadRequest, json!({
-            "error": "invalid filename"
-        })));
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Uploader {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Uploader {
+            ip: request.client_ip(),
+            user_agent: request.headers().get_one("User-Agent").map(str::to_string),
+        })
     }
+}
 
-    let filename = file.raw_name()
-        .map(|x| x.dangerous_unsafe_unsanitized_raw())
-        .map(|x| x.to_string());
+impl Staged {
+    pub fn get_path_to_staging_folder() -> PathBuf {
+        let mut path = crate::get_path_to_storage();
 
-    if filename.as_ref().is_none() {
-        log::info!("tried to upload file without filename or invalid filename");
+        path.push("staging");
 
-        return Err((Status::BadRequest, json!({
-            "error": "filename empty or invalid"
-        })));
+        path
+    }
+
+    pub fn new_path() -> PathBuf {
+        let mut path = Self::get_path_to_staging_folder();
+
+        path.push(new_token());
+
+        path
+    }
+}
+
+fn check_prefix_limits(prefix: &FilePrefix, filename: &str, staged: &Staged, sniffed: &Sniffed) -> Result<(), UploadError> {
+    if let Some(max_size) = prefix.max_size() {
+        if staged.len > max_size.as_u64() {
+            log::info!("upload for prefix '{}' exceeds {}", prefix.prefix(), max_size);
+
+            return Err(UploadError::FileTooLarge(max_size.as_u64()));
+        }
+    }
+
+    let extension = std::path::Path::new(filename)
+        .extension()
+        .and_then(|x| x.to_str());
+
+    if !prefix.allows_extension(extension) {
+        log::info!("upload for prefix '{}' has disallowed extension {:?}", prefix.prefix(), extension);
+
+        return Err(UploadError::ExtensionNotAllowed(prefix.allowed_extensions().to_vec()));
+    }
+
+    if !prefix.allows_mime_type(Some(&sniffed.mime_type)) {
+        log::info!("upload for prefix '{}' sniffed as disallowed type {:?}", prefix.prefix(), sniffed.mime_type);
+
+        return Err(UploadError::TypeNotAllowed(prefix.allowed_mime_types().to_vec()));
     }
 
-    let filename = filename.unwrap();
+    Ok(())
+}
+
+/// What was found out about a staged file's contents before storing it.
+struct Inspection {
+    sniffed: Sniffed,
+    verdict: Option<Verdict>,
+    /// Added by hooks.
+    tags: Vec<String>,
+}
+
+/// Metadata of `staged` stored as `stored_name`, without a delete token.
+fn describe_upload(staged: &Staged, inspection: &Inspection, sha256: String, stored_name: Option<String>) -> FileMeta {
+    FileMeta {
+        sha256,
+        size: staged.len,
+        uploaded_at: Utc::now(),
+        delete_token_hash: None,
+        withdraw_until: None,
+        original_name: Some(staged.raw_name.clone()),
+        stored_name,
+        uploader_ip: staged.uploader.ip,
+        user_agent: staged.uploader.user_agent.clone(),
+        content_type: staged.content_type.clone(),
+        sniffed_type: Some(inspection.sniffed.mime_type.clone()),
+        scan_verdict: inspection.verdict.as_ref().map(Verdict::describe),
+        tags: inspection.tags.clone(),
+    }
+}
+
+/// Moves a rejected staged file out of the scopes, keeping its metadata
+/// next to it for an admin to look at.
+async fn quarantine(staged: &Staged, inspection: &Inspection) -> io::Result<PathBuf> {
+    let folder = scan::get_path_to_quarantine_folder();
+
+    tokio::fs::create_dir_all(&folder).await?;
+
+    // The client's name may not even be valid, it's kept in the metadata.
+    let stored_name = new_token();
+    let path = folder.join(&stored_name);
+
+    // A staged file that's linked to a blob already is copied, so the blob
+    // can still be freed once its files are gone.
+    #[cfg(unix)]
+    let linked = {
+        use std::os::unix::fs::MetadataExt;
+
+        tokio::fs::metadata(&staged.path).await?.nlink() > 1
+    };
+    #[cfg(not(unix))]
+    let linked = false;
+
+    if linked {
+        tokio::fs::copy(&staged.path, &path).await?;
+    } else {
+        tokio::fs::rename(&staged.path, &path).await?;
+    }
+
+    let sha256 = meta::sha256_file(&path).await?;
+
+    describe_upload(staged, inspection, sha256, Some(stored_name)).save(&path).await?;
+
+    Ok(path)
+}
+
+/// Records the stored file's metadata and hands out a receipt with a fresh
+/// delete token.
+async fn issue_receipt(state: &AppState, path: &Path, staged: &Staged, inspection: &Inspection, sha256: String, scope: FileScope, recipient: Option<Arc<str>>) -> io::Result<Receipt> {
+    let stored_name = path.file_name().and_then(|x| x.to_str()).expect("invalid filename").to_string();
+
+    let delete_token = new_token();
+
+    let mut file_meta = describe_upload(staged, inspection, sha256, Some(stored_name.clone()));
+    let withdraw_until = file_meta.uploaded_at + state.config.withdraw_window();
 
-    if filename.len() > 64 {
+    file_meta.delete_token_hash = Some(meta::sha256_hex(delete_token.as_bytes()));
+    file_meta.withdraw_until = Some(withdraw_until);
+
+    file_meta.save(path).await?;
+
+    let mut receipt = Receipt {
+        filename: stored_name,
+        scope,
+        recipient,
+        size: file_meta.size,
+        sha256: file_meta.sha256,
+        uploaded_at: file_meta.uploaded_at,
+        withdraw_until,
+        delete_token: Some(delete_token),
+        signature: None,
+    };
+
+    state.signer.sign(&mut receipt);
+
+    Ok(receipt)
+}
+
+/// Routes to `recipient` when given, otherwise by the filename's prefix.
+/// A named recipient that doesn't exist is an error rather than falling
+/// back to the common scope.
+pub async fn route(state: &AppState, filename: &str, recipient: Option<&str>) -> Result<(FileScope, Option<Arc<User>>), UploadError> {
+    let users = state.users.read().await;
+
+    match recipient.filter(|x| !x.is_empty()) {
+        Some(recipient) => match users.list.get(recipient) {
+            Some(user) => Ok((FileScope::User, Some(user.clone()))),
+            None => {
+                log::info!("tried to upload to unknown recipient {:?}", recipient);
+
+                Err(UploadError::UnknownRecipient)
+            }
+        },
+        None => Ok(guess_scope_from_filename(filename, &users.prefix_map)),
+    }
+}
+
+/// Validates and routes a staged file, then moves it into its scope. The
+/// staged file is gone afterwards, whether storing it worked or not.
+pub async fn store(state: &AppState, staged: Staged) -> Result<Receipt, UploadError> {
+    let result = store_staged(state, &staged).await;
+
+    // Stored files are links to the blob, the staged one isn't needed either way.
+    let _ = tokio::fs::remove_file(&staged.path).await;
+
+    result
+}
+
+/// Length check and sanitizing of a client supplied filename.
+pub fn check_filename(raw_name: &str) -> Result<String, UploadError> {
+    if raw_name.len() > 64 {
         log::info!("tried to upload file with too long filename");
 
-        return Err((Status::BadRequest, json!({
-            "error": "filename too long"
-        })));
+        return Err(UploadError::InvalidFilename("filename too long"));
     }
 
-    let filename = sanitize_filename(filename);
+    let filename = sanitize_filename(raw_name);
 
     if filename.as_ref().is_none() {
         log::warn!("tried to upload file with invalid filename chars");
 
-        return Err((Status::BadRequest, json!({
-            "error": "invalid filename"
-        })));
+        return Err(UploadError::InvalidFilename("invalid filename"));
     }
 
-    let filename = filename.unwrap();
+    Ok(filename.unwrap())
+}
+
+/// Links `source` to `path` without ever replacing an existing file. When
+/// `path` is taken another name is picked according to `policy`.
+async fn place(source: &Path, path: &Path, millis: u128, filename: &str, policy: CollisionPolicy) -> Result<PathBuf, UploadError> {
+    const MAX_ATTEMPTS: u32 = 100;
+
+    let mut path = path.to_path_buf();
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        // Unlike `rename`, linking fails if the target exists.
+        match tokio::fs::hard_link(source, &path).await {
+            Ok(()) => return Ok(path),
+            Err(why) if why.kind() == io::ErrorKind::AlreadyExists => {
+                log::debug!("{:?} already exists", path);
+            }
+            Err(why) => {
+                log::warn!("uploaded file store error: {}", why);
+
+                return Err(why.into());
+            }
+        }
+
+        let candidate = match policy {
+            CollisionPolicy::Reject => {
+                log::info!("rejected upload colliding with {:?}", path);
+
+                return Err(UploadError::Collision);
+            }
+            CollisionPolicy::Suffix => match filename.rsplit_once('.') {
+                Some((stem, extension)) => format!("{}-{}-{}.{}", millis, stem, attempt, extension),
+                None => format!("{}-{}-{}", millis, filename, attempt),
+            },
+            CollisionPolicy::Version => format!("{}-{}", millis + u128::from(attempt), filename),
+        };
+
+        path.set_file_name(candidate);
+    }
+
+    log::warn!("gave up storing {:?} after {} collisions", filename, MAX_ATTEMPTS);
+
+    Err(UploadError::Collision)
+}
+
+async fn store_staged(state: &AppState, staged: &Staged) -> Result<Receipt, UploadError> {
+    let filename = check_filename(&staged.raw_name)?;
 
     let ts = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("time went backwards");
 
-    let path = {
-        let (scope, user) = guess_scope_from_filename(
-            &filename, &state.prefix_map,
-        );
+    let (scope, user) = route(state, &filename, staged.recipient.as_deref()).await?;
+
+    // No amount of cleaning up makes room for this one.
+    if let Some(max) = state.config.quota_for(user.as_deref()).max_bytes() {
+        if staged.len > max.as_u64() {
+            log::info!("rejected upload of {:?} larger than the whole quota", filename);
+
+            return Err(UploadError::FileTooLarge(max.as_u64()));
+        }
+    }
+
+    let sniffed = sniff::sniff(&staged.path).await?;
+
+    if !state.config.content_policy(scope).allows(&sniffed) {
+        log::info!("rejected upload of {:?} sniffed as {:?} to {:?} scope", filename, sniffed, scope);
+
+        return Err(UploadError::ContentNotAllowed(sniffed.mime_type));
+    }
+
+    // Prefixes check the sniffed type too, not whatever the client claimed.
+    if let Some(user) = &user {
+        match user.find_prefix(&filename) {
+            Some(prefix) => check_prefix_limits(prefix, &filename, staged, &sniffed)?,
+            None if user.requires_prefix() => {
+                log::info!("upload of {:?} to {:?} without one of their prefixes", filename, user.username());
+
+                return Err(UploadError::InvalidFilename("filename must start with one of the recipient's prefixes"));
+            }
+            None => {}
+        }
+    }
+
+    let verdict = match scan::scan(state.config.scan(), &staged.path).await {
+        Ok(verdict) => verdict,
+        Err(why) if state.config.scan().fail_open() => {
+            log::warn!("failed to scan {:?}, accepting it unscanned: {}", filename, why);
+
+            Some(Verdict::Unscanned(why.to_string()))
+        }
+        Err(why) => {
+            log::warn!("failed to scan {:?}: {}", filename, why);
+
+            return Err(UploadError::ScanFailed);
+        }
+    };
 
+    let mut inspection = Inspection {
+        sniffed,
+        verdict,
+        tags: vec![],
+    };
+
+    if let Some(Verdict::Infected(signature)) = &inspection.verdict {
+        log::warn!("upload of {:?} infected with {}", filename, signature);
+
+        match quarantine(staged, &inspection).await {
+            Ok(path) => log::info!("quarantined upload of {:?} as {:?}", filename, path),
+            Err(why) => log::warn!("failed to quarantine upload of {:?}: {}", filename, why),
+        }
+
+        return Err(UploadError::Infected(signature.clone()));
+    }
+
+    let sha256 = meta::sha256_file(&staged.path).await?;
+
+    let quota = state.config.quota_for(user.as_deref());
+    let usage_key = user.as_ref().map(|user| user.username());
+    let len = staged.len;
+
+    let path = {
         let filename = format!("{}-{}", ts.as_millis(), filename);
         scope.get_path_to_file(&filename, user)
     };
 
-
     log::debug!("will store file @ {:?}", path);
 
-    if let Err(why) = file.persist_to(&path).await {
-        log::warn!("uploaded file store error: {}", why);
+    let folder = path.parent().expect("file path without folder");
+    let reserved = UsageVec::reserve(&state.usage, &usage_key, folder, &quota, len).await;
+
+    if let Err(usage) = reserved {
+        log::info!("upload to {:?} rejected, quota exceeded", folder);
+
+        return Err(UploadError::QuotaExceeded(usage));
+    }
+
+    let blob_guard = state.blob_locks.lock(&sha256).await;
+
+    let blob = blob::insert(&staged.path, &sha256).await;
+
+    if let Err(why) = blob {
+        log::warn!("failed to store blob of {:?}: {}", staged.path, why);
+
+        state.usage.write().await.release(&usage_key, len);
+
+        return Err(why.into());
+    }
+
+    let blob = blob.unwrap();
+
+    let placed = place(&blob, &path, ts.as_millis(), &filename, state.config.collision_policy()).await;
+
+    // The staged file links to a new blob as well, it has to go first.
+    if let Err(why) = placed {
+        let _ = tokio::fs::remove_file(&staged.path).await;
+        blob::release(&sha256).await;
+        state.usage.write().await.release(&usage_key, len);
+
+        return Err(why);
+    }
+
+    let path = placed.unwrap();
+
+    let receipt = issue_receipt(state, &path, staged, &inspection, sha256.clone(), scope, usage_key.clone()).await;
+
+    drop(blob_guard);
+
+    if let Err(why) = receipt {
+        log::warn!("failed to record metadata of {:?}: {}", path, why);
+
+        unstore(state, &path, staged, &sha256, &usage_key, len).await;
+
+        return Err(why.into());
+    }
+
+    // Hooks see the file where it's stored, and only files that made it.
+    if let Err(why) = run_hooks(state, &path, &filename, &mut inspection).await {
+        if let UploadError::RejectedByHook(..) = why {
+            match quarantine(staged, &inspection).await {
+                Ok(path) => log::info!("quarantined upload of {:?} as {:?}", filename, path),
+                Err(why) => log::warn!("failed to quarantine upload of {:?}: {}", filename, why),
+            }
+        }
+
+        unstore(state, &path, staged, &sha256, &usage_key, len).await;
+
+        return Err(why);
+    }
+
+    Ok(receipt.unwrap())
+}
+
+/// Runs the configured hooks on a stored file in order, recording the tags
+/// they add in its metadata.
+async fn run_hooks(state: &AppState, path: &Path, filename: &str, inspection: &mut Inspection) -> Result<(), UploadError> {
+    if state.config.hooks().is_empty() {
+        return Ok(());
+    }
+
+    let mut file_meta = FileMeta::load(path).await?;
+
+    for hook in state.config.hooks() {
+        let decision = match hook.run(path, &file_meta).await {
+            Ok(decision) => decision,
+            Err(why) if hook.fail_open() => {
+                log::warn!("hook '{}' failed on {:?}, skipping it: {}", hook.name(), filename, why);
+
+                continue;
+            }
+            Err(why) => {
+                log::warn!("hook '{}' failed on {:?}: {}", hook.name(), filename, why);
+
+                return Err(UploadError::HookFailed(hook.name().to_string()));
+            }
+        };
+
+        match decision {
+            Decision::Accept => {}
+            Decision::Tag(tags) => {
+                inspection.tags.extend(tags.iter().cloned());
+                file_meta.tags.extend(tags);
+            }
+            Decision::Reject(reason) => {
+                log::info!("hook '{}' rejected upload of {:?}: {:?}", hook.name(), filename, reason);
+
+                return Err(UploadError::RejectedByHook(hook.name().to_string(), reason));
+            }
+        }
+    }
+
+    if !file_meta.tags.is_empty() {
+        file_meta.save(path).await?;
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Takes back a stored file along with everything accounted for it.
+async fn unstore(state: &AppState, path: &Path, staged: &Staged, sha256: &str, usage_key: &Option<Arc<str>>, len: u64) {
+    let _ = tokio::fs::remove_file(path).await;
+    let _ = FileMeta::remove(path).await;
+
+    // The staged file links to the blob too, it has to go first.
+    let _ = tokio::fs::remove_file(&staged.path).await;
+
+    let _blob_guard = state.blob_locks.lock(sha256).await;
+    blob::release(sha256).await;
+
+    state.usage.write().await.release(usage_key, len);
+}
+
+/// Stages and stores a single file of a multipart upload.
+async fn store_temp_file(state: &AppState, file: &mut TempFile<'_>, recipient: Option<&str>, uploader: &Uploader) -> Result<Receipt, UploadError> {
+    if file.name().is_none() {
+        log::warn!("tried to upload file with invalid or missing filename");
+
+        return Err(UploadError::InvalidFilename("invalid filename"));
+    }
+
+    let filename = file.raw_name()
+        .map(|x| x.dangerous_unsafe_unsanitized_raw())
+        .map(|x| x.to_string());
+
+    if filename.as_ref().is_none() {
+        log::info!("tried to upload file without filename or invalid filename");
+
+        return Err(UploadError::InvalidFilename("filename empty or invalid"));
+    }
+
+    let staged = Staged {
+        path: Staged::new_path(),
+        raw_name: filename.unwrap(),
+        content_type: file.content_type().map(|x| format!("{}/{}", x.top(), x.sub())),
+        len: file.len(),
+        recipient: recipient.map(str::to_string),
+        uploader: uploader.clone(),
+    };
+
+    if let Err(why) = file.persist_to(&staged.path).await {
+        log::warn!("uploaded file staging error: {}", why);
+
+        return Err(why.into());
+    }
+
+    store(state, staged).await
+}
+
+/// Stores every file of the form independently and reports on each, in
+/// order. Responds 200 when all of them were stored and 207 when only some
+/// were. When none were, the first file's error decides the status, so a
+/// single file fails just like it would on its own.
+#[post("/upload", data = "<form>")]
+pub async fn upload(mut form: Form<UploadData<'_>>, uploader: Uploader, state: &State<AppState>) -> Result<(Status, Value), UploadError> {
+    if form.file.is_empty() {
+        log::info!("tried to upload without any files");
+
+        return Err(UploadError::InvalidFilename("no files given"));
+    }
+
+    let form = &mut *form;
+
+    // An unknown recipient fails the whole request rather than every file.
+    route(state, "", form.recipient.as_deref()).await?;
+
+    let mut results = Vec::with_capacity(form.file.len());
+    let mut stored = 0;
+    let mut first_error = None;
+
+    for file in form.file.iter_mut() {
+        let name = file.raw_name()
+            .map(|x| x.dangerous_unsafe_unsanitized_raw().to_string());
+
+        let result = match store_temp_file(state, file, form.recipient.as_deref(), &uploader).await {
+            Ok(receipt) => {
+                stored += 1;
+
+                json!({
+                    "name": name,
+                    "status": Status::Ok.code,
+                    "receipt": receipt,
+                })
+            }
+            Err(why) => {
+                let mut body = why.body();
+                body["name"] = json!(name);
+                body["status"] = json!(why.status().code);
+
+                first_error.get_or_insert(why);
+
+                body
+            }
+        };
+
+        results.push(result);
+    }
+
+    let status = match first_error {
+        None => Status::Ok,
+        Some(_) if stored > 0 => Status::MultiStatus,
+        Some(why) => {
+            let mut body = why.body();
+            body["files"] = json!(results);
+
+            return Ok((why.status(), body));
+        }
+    };
+
+    Ok((status, json!({
+        "files": results
+    })))
+}
+
+/// Raw body upload for scripts, e.g. `curl -T report.pdf <host>/ajax/upload/`.
+#[put("/upload/<filename>?<recipient>", data = "<data>")]
+pub async fn upload_raw(filename: &str, recipient: Option<&str>, data: Data<'_>, content_type: Option<&ContentType>, limits: &Limits, uploader: Uploader, state: &State<AppState>) -> Result<Json<Receipt>, UploadError> {
+    // Fail before reading the body if the name or recipient won't do anyway.
+    check_filename(filename)?;
+    route(state, filename, recipient).await?;
+
+    let limit = limits.get("file").unwrap_or(Limits::FILE);
+    let path = Staged::new_path();
+
+    let written = data.open(limit).into_file(&path).await;
+
+    if let Err(why) = written {
+        log::warn!("uploaded file staging error: {}", why);
+
+        let _ = tokio::fs::remove_file(&path).await;
+
+        return Err(why.into());
+    }
+
+    if !written.unwrap().is_complete() {
+        log::info!("raw upload of {:?} exceeds {}", filename, limit);
+
+        let _ = tokio::fs::remove_file(&path).await;
+
+        return Err(UploadError::FileTooLarge(limit.as_u64()));
+    }
+
+    let len = match tokio::fs::metadata(&path).await {
+        Ok(metadata) => metadata.len(),
+        Err(why) => {
+            log::warn!("uploaded file staging error: {}", why);
+
+            let _ = tokio::fs::remove_file(&path).await;
+
+            return Err(why.into());
+        }
+    };
+
+    let staged = Staged {
+        len,
+        path,
+        raw_name: filename.to_string(),
+        content_type: content_type.map(|x| format!("{}/{}", x.top(), x.sub())),
+        recipient: recipient.map(str::to_string),
+        uploader,
+    };
+
+    store(state, staged).await.map(Json)
+}
+
+#[post("/upload/withdraw", data = "<form>")]
+pub async fn withdraw(form: Form<WithdrawData<'_>>, state: &State<AppState>) -> Result<Status, (Status, Value)> {
+    // Stored names are sanitized already, anything else can't be ours.
+    if form.filename.starts_with('.') || sanitize_filename(form.filename).as_deref() != Some(form.filename) {
+        log::debug!("tried to withdraw invalid filename {:?}", form.filename);
+
+        return Err((Status::BadRequest, json!({
+            "error": "invalid filename"
+        })));
+    }
+
+    let user = match (form.scope, form.recipient) {
+        (FileScope::Common, _) => None,
+        (FileScope::User, Some(recipient)) => state.users.read().await.list.get(recipient).cloned(),
+        (FileScope::User, None) => None,
+    };
+
+    if form.scope == FileScope::User && user.is_none() {
+        return Err((Status::NotFound, json!({
+            "error": "unknown recipient"
+        })));
+    }
+
+    let usage_key = user.as_ref().map(|user| user.username());
+    let path = form.scope.get_path_to_file(form.filename, user);
+
+    let file_meta = FileMeta::load(&path).await;
+
+    if file_meta.as_ref().map_or(true, |x| !x.allows_withdrawal(form.token)) {
+        log::debug!("rejected withdrawal of {:?}", path);
+
+        return Err((Status::Forbidden, json!({
+            "error": "invalid token or withdrawal window closed"
+        })));
+    }
+
+    if let Err(why) = tokio::fs::remove_file(&path).await {
+        log::warn!("failed to withdraw {:?}: {}", path, why);
+
+        return Err((Status::InternalServerError, json!({
+            "error": "failed to withdraw file"
+        })));
+    }
+
+    let file_meta = file_meta.unwrap();
+
+    let _ = FileMeta::remove(&path).await;
+
+    let _blob_guard = state.blob_locks.lock(&file_meta.sha256).await;
+    blob::release(&file_meta.sha256).await;
+
+    state.usage.write().await.release(&usage_key, file_meta.size);
+
+    log::info!("withdrew {:?}", path);
+
+    Ok(Status::NoContent)
+}
+
+/// Helpers for tests that store real uploads.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+
+    /// A user with a fresh folder of their own, so tests storing files in
+    /// parallel don't see each other's uploads.
+    pub struct ScratchUser {
+        pub user: User,
+    }
+
+    impl ScratchUser {
+        pub async fn new() -> Self {
+            let user = User::new(&format!("test-{}", &new_token()[..12]));
+
+            tokio::fs::create_dir_all(user.get_path_to_user_folder()).await.unwrap();
+            tokio::fs::create_dir_all(Staged::get_path_to_staging_folder()).await.unwrap();
+
+            Self { user }
+        }
+
+        /// Names of the files stored for the user so far.
+        pub async fn stored(&self) -> Vec<String> {
+            let mut names = vec![];
+            let mut rdir = tokio::fs::read_dir(self.user.get_path_to_user_folder()).await.unwrap();
+
+            while let Some(entry) = rdir.next_entry().await.unwrap() {
+                let name = entry.file_name().into_string().unwrap();
+
+                if !name.starts_with('.') {
+                    names.push(name);
+                }
+            }
+
+            names
+        }
+
+        /// Removes the user's folder and frees the blobs of their files.
+        pub async fn cleanup(self) {
+            for name in self.stored().await {
+                let path = self.user.get_path_to_user_file(&name);
+
+                if let Ok(file_meta) = FileMeta::load(&path).await {
+                    let _ = tokio::fs::remove_file(&path).await;
+                    blob::release(&file_meta.sha256).await;
+                }
+            }
+
+            let _ = tokio::fs::remove_dir_all(self.user.get_path_to_user_folder()).await;
+        }
+    }
+
+    /// Writes `contents` to a new staged file bound for `user`.
+    pub async fn stage(user: &User, raw_name: &str, contents: &[u8]) -> Staged {
+        let path = Staged::new_path();
+
+        tokio::fs::write(&path, contents).await.unwrap();
+
+        Staged {
+            recipient: Some(user.username().to_string()),
+            ..staged(raw_name, path, contents.len() as u64)
+        }
+    }
+
+    /// Takes the quarantined upload the client called `original_name` out
+    /// of quarantine, returning its metadata.
+    pub async fn unquarantine(original_name: &str) -> Option<FileMeta> {
+        let mut rdir = tokio::fs::read_dir(scan::get_path_to_quarantine_folder()).await.ok()?;
+
+        while let Some(entry) = rdir.next_entry().await.unwrap() {
+            let path = entry.path();
+
+            if entry.file_name().to_str().is_none_or(|x| x.starts_with('.')) {
+                continue;
+            }
+
+            match FileMeta::load(&path).await {
+                Ok(file_meta) if file_meta.original_name.as_deref() == Some(original_name) => {
+                    let _ = tokio::fs::remove_file(&path).await;
+                    let _ = FileMeta::remove(&path).await;
+
+                    return Some(file_meta);
+                }
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    pub fn staged(raw_name: &str, path: PathBuf, len: u64) -> Staged {
+        Staged {
+            path,
+            raw_name: raw_name.to_string(),
+            content_type: None,
+            len,
+            recipient: None,
+            uploader: Uploader {
+                ip: None,
+                user_agent: None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::json;
+
+    use crate::config::Config;
+
+    use super::*;
+    use super::testing::{stage, staged, ScratchUser};
+
+    /// A user whose folder is a plain file, which not even root can store
+    /// into. The file is removed again when the guard drops.
+    struct UnwritableUser {
+        user: User,
+    }
+
+    impl UnwritableUser {
+        async fn new() -> Self {
+            let user = User::new(&format!("unwritable-{}", &new_token()[..12]));
+
+            let folder = user.get_path_to_user_folder();
+
+            tokio::fs::create_dir_all(folder.parent().unwrap()).await.unwrap();
+            tokio::fs::write(&folder, b"").await.unwrap();
+
+            Self { user }
+        }
+    }
+
+    impl Drop for UnwritableUser {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(self.user.get_path_to_user_folder());
+        }
+    }
+
+    /// A form as `/upload` takes it, with `files` as (filename, contents).
+    fn multipart(files: &[(&str, &str)], recipient: &str) -> String {
+        let mut body = String::new();
+
+        for (filename, contents) in files {
+            body += "--BOUNDARY\r\n";
+            body += &format!("Content-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n", filename);
+            body += &format!("Content-Type: text/plain\r\n\r\n{}\r\n", contents);
+        }
+
+        body += "--BOUNDARY\r\n";
+        body += &format!("Content-Disposition: form-data; name=\"recipient\"\r\n\r\n{}\r\n", recipient);
+        body += "--BOUNDARY--\r\n";
+
+        body
+    }
+
+    #[test]
+    fn maps_io_errors() {
+        let denied = UploadError::from(io::Error::from(io::ErrorKind::PermissionDenied));
+
+        assert_eq!(denied.status(), Status::InternalServerError);
+        assert_eq!(denied.body()["error"], "server storage not writable");
+
+        #[cfg(unix)]
+        assert_eq!(UploadError::from(io::Error::from_raw_os_error(28)).status(), Status::InsufficientStorage);
+    }
+
+    #[rocket::async_test]
+    async fn places_colliding_names_by_policy() {
+        let folder = std::env::temp_dir().join(format!("dumpster-{}", new_token()));
+        let source = folder.join("source");
+        let taken = folder.join("1000-report.txt");
+
+        tokio::fs::create_dir_all(&folder).await.unwrap();
+        tokio::fs::write(&source, b"new").await.unwrap();
+        tokio::fs::write(&taken, b"old").await.unwrap();
+
+        let rejected = place(&source, &taken, 1000, "report.txt", CollisionPolicy::Reject).await;
+        let suffixed = place(&source, &taken, 1000, "report.txt", CollisionPolicy::Suffix).await.unwrap();
+        let versioned = place(&source, &taken, 1000, "report.txt", CollisionPolicy::Version).await.unwrap();
+
+        let old = tokio::fs::read(&taken).await.unwrap();
+        tokio::fs::remove_dir_all(&folder).await.unwrap();
+
+        assert!(matches!(rejected, Err(UploadError::Collision)));
+        assert_eq!(suffixed, folder.join("1000-report-1.txt"));
+        assert_eq!(versioned, folder.join("1001-report.txt"));
+        assert_eq!(old, b"old");
+    }
+
+    #[rocket::async_test]
+    async fn routes_to_named_recipients_before_prefixes() {
+        let mut alice = User::new("alice");
+        alice.set_prefixes(vec![FilePrefix::new("alice_")]);
+
+        let state = AppState::for_tests(Config::default(), vec![alice, User::new("bob")]);
+
+        let (scope, user) = route(&state, "alice_report.txt", None).await.unwrap();
+        assert_eq!((scope, user.unwrap().username()), (FileScope::User, "alice".into()));
+
+        let (scope, user) = route(&state, "alice_report.txt", Some("bob")).await.unwrap();
+        assert_eq!((scope, user.unwrap().username()), (FileScope::User, "bob".into()));
+
+        let (scope, user) = route(&state, "report.txt", Some("")).await.unwrap();
+        assert_eq!(scope, FileScope::Common);
+        assert!(user.is_none());
+
+        let unknown = route(&state, "alice_report.txt", Some("mallory")).await;
+        assert!(matches!(unknown, Err(UploadError::UnknownRecipient)));
+    }
+
+    #[rocket::async_test]
+    async fn keeps_prefix_limits_for_named_recipients() {
+        let mut scratch = ScratchUser::new().await;
+        let prefix = toml::from_str::<FilePrefix>("prefix = \"hw1_\"\nmaxSize = 4").unwrap();
+        scratch.user.set_prefixes(vec![prefix]);
+
+        let state = AppState::for_tests(Config::default(), vec![scratch.user.clone()]);
+
+        let unprefixed = store(&state, stage(&scratch.user, "report.txt", b"answers").await).await;
+        let too_large = store(&state, stage(&scratch.user, "hw1_report.txt", b"answers").await).await;
+        let fitting = store(&state, stage(&scratch.user, "hw1_report.txt", b"42").await).await;
+
+        assert!(matches!(unprefixed, Err(UploadError::InvalidFilename(_))));
+        assert!(matches!(too_large, Err(UploadError::FileTooLarge(4))));
+        assert!(fitting.is_ok());
+        assert_eq!(scratch.stored().await.len(), 1);
+
+        scratch.cleanup().await;
+    }
+
+    #[rocket::async_test]
+    async fn checks_prefix_types_against_sniffed_contents() {
+        let mut scratch = ScratchUser::new().await;
+        let prefix = toml::from_str::<FilePrefix>("prefix = \"hw1_\"\nmimeTypes = [\"application/pdf\"]").unwrap();
+        scratch.user.set_prefixes(vec![prefix]);
+
+        let state = AppState::for_tests(Config::default(), vec![scratch.user.clone()]);
+
+        let disguised = Staged {
+            content_type: Some("application/pdf".to_string()),
+            ..stage(&scratch.user, "hw1_report.pdf", b"#!/bin/sh\necho hi\n").await
+        };
+
+        // Raw uploads usually come without a declared type at all.
+        let undeclared = stage(&scratch.user, "hw1_report.pdf", b"%PDF-1.4\n%%EOF\n").await;
+
+        assert!(matches!(store(&state, disguised).await, Err(UploadError::TypeNotAllowed(_))));
+        assert!(store(&state, undeclared).await.is_ok());
+
+        scratch.cleanup().await;
+    }
+
+    #[rocket::async_test]
+    async fn reports_storage_errors() {
+        let unwritable = UnwritableUser::new().await;
+        let username = unwritable.user.username();
+
+        let state = AppState::for_tests(Config::default(), vec![unwritable.user.clone()]);
+        let rocket = rocket::build().manage(state).mount("/", routes![upload]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let response = client.post("/upload")
+            .header(Header::new("Content-Type", "multipart/form-data; boundary=BOUNDARY"))
+            .body(multipart(&[("report.txt", "some report")], &username))
+            .dispatch().await;
+
+        assert_eq!(response.status(), Status::InternalServerError);
+
+        let body = json::from_str::<Value>(&response.into_string().await.unwrap()).unwrap();
+
+        assert_eq!(body["error"], "failed to store file");
+        assert_eq!(body["files"][0]["status"], 500);
+        assert_eq!(body["files"][0]["name"], "report.txt");
+    }
+
+    #[rocket::async_test]
+    async fn stores_every_file_of_a_form() {
+        let scratch = ScratchUser::new().await;
+        let username = scratch.user.username();
+
+        let state = AppState::for_tests(Config::default(), vec![scratch.user.clone()]);
+        let rocket = rocket::build().manage(state).mount("/", routes![upload]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let response = client.post("/upload")
+            .header(Header::new("Content-Type", "multipart/form-data; boundary=BOUNDARY"))
+            .body(multipart(&[("first.txt", "first"), ("second.txt", "second")], &username))
+            .dispatch().await;
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let body = json::from_str::<Value>(&response.into_string().await.unwrap()).unwrap();
+
+        assert_eq!(body["files"][0]["receipt"]["size"], 5);
+        assert_eq!(body["files"][1]["receipt"]["size"], 6);
+        assert_eq!(scratch.stored().await.len(), 2);
+
+        scratch.cleanup().await;
+    }
+
+    #[rocket::async_test]
+    async fn reports_each_file_of_a_form() {
+        let scratch = ScratchUser::new().await;
+        let username = scratch.user.username();
+
+        let state = AppState::for_tests(Config::default(), vec![scratch.user.clone()]);
+        let rocket = rocket::build().manage(state).mount("/", routes![upload]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let too_long = format!("{}.txt", "x".repeat(64));
+
+        let response = client.post("/upload")
+            .header(Header::new("Content-Type", "multipart/form-data; boundary=BOUNDARY"))
+            .body(multipart(&[("report.txt", "some report"), (&too_long, "rejected")], &username))
+            .dispatch().await;
+
+        assert_eq!(response.status(), Status::MultiStatus);
+
+        let body = json::from_str::<Value>(&response.into_string().await.unwrap()).unwrap();
+
+        assert_eq!(body["files"][0]["status"], 200);
+        assert_eq!(body["files"][1]["status"], 400);
+        assert_eq!(body["files"][1]["error"], "filename too long");
+        assert_eq!(scratch.stored().await.len(), 1);
+
+        scratch.cleanup().await;
+    }
+
+    #[rocket::async_test]
+    async fn fails_forms_where_no_file_was_stored() {
+        let scratch = ScratchUser::new().await;
+        let username = scratch.user.username();
+
+        let state = AppState::for_tests(Config::default(), vec![scratch.user.clone()]);
+        let rocket = rocket::build().manage(state).mount("/", routes![upload]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let too_long = format!("{}.txt", "x".repeat(64));
+
+        let response = client.post("/upload")
+            .header(Header::new("Content-Type", "multipart/form-data; boundary=BOUNDARY"))
+            .body(multipart(&[(&too_long, "rejected"), (&too_long, "rejected again")], &username))
+            .dispatch().await;
+
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let body = json::from_str::<Value>(&response.into_string().await.unwrap()).unwrap();
+
+        assert_eq!(body["error"], "filename too long");
+        assert_eq!(body["files"].as_array().unwrap().len(), 2);
+        assert!(scratch.stored().await.is_empty());
+
+        scratch.cleanup().await;
+    }
+
+    #[rocket::async_test]
+    async fn stores_raw_uploads() {
+        let scratch = ScratchUser::new().await;
+        let username = scratch.user.username();
+
+        let state = AppState::for_tests(Config::default(), vec![scratch.user.clone()]);
+        let rocket = rocket::build().manage(state).mount("/", routes![upload_raw]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let response = client.put(format!("/upload/report.txt?recipient={}", username))
+            .header(ContentType::Plain)
+            .body("some report")
+            .dispatch().await;
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let receipt = json::from_str::<Value>(&response.into_string().await.unwrap()).unwrap();
+
+        assert_eq!(receipt["size"], 11);
+        assert_eq!(receipt["sha256"], meta::sha256_hex(b"some report"));
+        assert_eq!(scratch.stored().await, [receipt["filename"].as_str().unwrap()]);
+
+        scratch.cleanup().await;
+    }
+
+    #[rocket::async_test]
+    async fn refuses_raw_uploads_over_the_limit() {
+        let scratch = ScratchUser::new().await;
+        let username = scratch.user.username();
+
+        let state = AppState::for_tests(Config::default(), vec![scratch.user.clone()]);
+        let figment = rocket::Config::figment().merge(("limits.file", 4));
+        let rocket = rocket::custom(figment).manage(state).mount("/", routes![upload_raw]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let response = client.put(format!("/upload/report.txt?recipient={}", username))
+            .body("some report")
+            .dispatch().await;
+
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+        assert!(scratch.stored().await.is_empty());
+
+        scratch.cleanup().await;
+    }
+
+    #[rocket::async_test]
+    async fn refuses_files_larger_than_the_whole_quota() {
+        let config = toml::from_str::<Config>("[quota.common]\nmax_bytes = 100").unwrap();
+        let state = AppState::for_tests(config, vec![]);
+
+        let result = store(&state, staged("report.pdf", Staged::new_path(), 101)).await;
+
+        assert!(matches!(result, Err(UploadError::FileTooLarge(100))));
+        assert_eq!(result.err().unwrap().status(), Status::PayloadTooLarge);
+    }
+}
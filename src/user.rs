@@ -1,25 +1,299 @@
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use rocket::data::ByteUnit;
+use rocket::http::Status;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{Error as _, Unexpected};
 
-#[derive(Deserialize, Serialize)]
+use crate::config::{Argon2Config, Config};
+use crate::quota::Quota;
+
+const ADMIN_ROLE: &str = "admin";
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct User {
     username: Arc<str>,
     password: Option<String>,
 
-    file_prefixes: Vec<Arc<str>>,
+    #[serde(default)]
+    disabled: bool,
+    expires_at: Option<DateTime<Utc>>,
+
+    #[serde(default)]
+    roles: Vec<Arc<str>>,
+    #[serde(default)]
+    groups: Vec<Arc<str>>,
 
     hashed_password: Option<String>,
+
+    // Tables have to come after plain values in TOML.
+    #[serde(serialize_with = "serialize_prefixes")]
+    file_prefixes: Vec<FilePrefix>,
+    quota: Option<Quota>,
+
+    #[serde(skip)]
+    source: Option<PathBuf>,
+}
+
+/// Why a plaintext password wasn't hashed.
+#[derive(Debug)]
+pub enum PasswordError {
+    /// It violates the password policy.
+    Policy(String),
+    /// Hashing itself failed.
+    Hash(String),
+}
+
+impl std::fmt::Display for PasswordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Policy(why) => write!(f, "{}", why),
+            Self::Hash(why) => write!(f, "failed to hash password: {}", why),
+        }
+    }
+}
+
+impl PasswordError {
+    pub fn status(&self) -> Status {
+        match self {
+            Self::Policy(_) => Status::UnprocessableEntity,
+            Self::Hash(_) => Status::InternalServerError,
+        }
+    }
+}
+
+impl From<PasswordError> for String {
+    fn from(why: PasswordError) -> Self {
+        why.to_string()
+    }
+}
+
+/// Whether `mime_type` is `pattern`, which may end in a `/*` wildcard such
+/// as `image/*`.
+pub fn mime_type_matches(pattern: &str, mime_type: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(top) => mime_type.split('/').next().is_some_and(|x| x.eq_ignore_ascii_case(top)),
+        None => pattern.eq_ignore_ascii_case(mime_type),
+    }
+}
+
+/// A filename prefix routing uploads to its user, optionally with limits
+/// of its own. Written as a plain string when it has no limits.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(from = "FilePrefixDef", into = "FilePrefixDef")]
+pub struct FilePrefix {
+    prefix: Arc<str>,
+    max_size: Option<ByteUnit>,
+    extensions: Vec<Arc<str>>,
+    mime_types: Vec<Arc<str>>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum FilePrefixDef {
+    Plain(Arc<str>),
+    /// camelCase to match the admin API; user files from before that still
+    /// load through the snake_case aliases.
+    #[serde(rename_all = "camelCase")]
+    Limited {
+        prefix: Arc<str>,
+        #[serde(default, alias = "max_size", deserialize_with = "byte_unit")]
+        max_size: Option<ByteUnit>,
+        #[serde(default)]
+        extensions: Vec<Arc<str>>,
+        #[serde(default, alias = "mime_types")]
+        mime_types: Vec<Arc<str>>,
+    },
+}
+
+/// `ByteUnit` asks for a `u64` and only gets to parse strings like `5MiB`
+/// if the format hands them over anyway, which the buffered input of an
+/// untagged enum doesn't.
+fn byte_unit<'de, D>(deserializer: D) -> Result<Option<ByteUnit>, D::Error>
+    where D: Deserializer<'de>
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match Option::<Raw>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Raw::Bytes(bytes)) => Ok(Some(bytes.into())),
+        Some(Raw::Text(text)) => text.parse()
+            .map(Some)
+            .map_err(|_| D::Error::invalid_value(Unexpected::Str(&text), &"byte unit string")),
+    }
+}
+
+impl From<FilePrefixDef> for FilePrefix {
+    fn from(def: FilePrefixDef) -> Self {
+        match def {
+            FilePrefixDef::Plain(prefix) => FilePrefix::new(prefix),
+            FilePrefixDef::Limited { prefix, max_size, extensions, mime_types } => FilePrefix {
+                prefix,
+                max_size,
+                extensions,
+                mime_types,
+            },
+        }
+    }
+}
+
+impl From<FilePrefix> for FilePrefixDef {
+    fn from(prefix: FilePrefix) -> Self {
+        if !prefix.has_limits() {
+            return FilePrefixDef::Plain(prefix.prefix);
+        }
+
+        FilePrefixDef::Limited {
+            prefix: prefix.prefix,
+            max_size: prefix.max_size,
+            extensions: prefix.extensions,
+            mime_types: prefix.mime_types,
+        }
+    }
+}
+
+impl FilePrefix {
+    pub fn new(prefix: impl Into<Arc<str>>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            max_size: None,
+            extensions: vec![],
+            mime_types: vec![],
+        }
+    }
+
+    pub fn prefix(&self) -> &Arc<str> {
+        &self.prefix
+    }
+
+    pub fn max_size(&self) -> Option<ByteUnit> {
+        self.max_size
+    }
+
+    pub fn has_limits(&self) -> bool {
+        self.max_size.is_some() || !self.extensions.is_empty() || !self.mime_types.is_empty()
+    }
+
+    pub fn allows_extension(&self, extension: Option<&str>) -> bool {
+        if self.extensions.is_empty() {
+            return true;
+        }
+
+        extension.is_some_and(|extension| {
+            self.extensions.iter().any(|x| x.eq_ignore_ascii_case(extension))
+        })
+    }
+
+    /// Matches `mime_type` against the allowed types, which may end in a
+    /// `/*` wildcard such as `image/*`.
+    pub fn allows_mime_type(&self, mime_type: Option<&str>) -> bool {
+        if self.mime_types.is_empty() {
+            return true;
+        }
+
+        mime_type.is_some_and(|mime_type| {
+            self.mime_types.iter().any(|allowed| mime_type_matches(allowed, mime_type))
+        })
+    }
+
+    pub fn allowed_extensions(&self) -> &[Arc<str>] {
+        &self.extensions
+    }
+
+    pub fn allowed_mime_types(&self) -> &[Arc<str>] {
+        &self.mime_types
+    }
+}
+
+/// TOML arrays can't mix strings and tables, so prefixes are written as
+/// tables as soon as one of them has limits.
+fn serialize_prefixes<S: Serializer>(prefixes: &[FilePrefix], serializer: S) -> Result<S::Ok, S::Error> {
+    if prefixes.iter().any(FilePrefix::has_limits) {
+        let tables = prefixes
+            .iter()
+            .map(|prefix| FilePrefixDef::Limited {
+                prefix: prefix.prefix.clone(),
+                max_size: prefix.max_size,
+                extensions: prefix.extensions.clone(),
+                mime_types: prefix.mime_types.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        return tables.serialize(serializer);
+    }
+
+    prefixes.serialize(serializer)
 }
 
 impl User {
+    pub fn new(username: &str) -> Self {
+        Self {
+            username: username.into(),
+            password: None,
+            disabled: false,
+            expires_at: None,
+            file_prefixes: vec![],
+            quota: None,
+            roles: vec![],
+            groups: vec![],
+            hashed_password: None,
+            source: None,
+        }
+    }
+
+    /// Usernames double as folder and file names, so only a conservative
+    /// set of characters is allowed.
+    pub fn is_valid_username(username: &str) -> bool {
+        !username.is_empty()
+            && username.len() <= 64
+            && !username.starts_with('.')
+            && username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    }
+
     pub fn username(&self) -> Arc<str> {
         self.username.clone()
     }
 
+    pub fn is_admin(&self) -> bool {
+        self.roles.iter().any(|role| role.as_ref() == ADMIN_ROLE)
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+
+    pub fn set_disabled(&mut self, disabled: bool) {
+        self.disabled = disabled;
+    }
+
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires_at
+    }
+
+    pub fn set_expires_at(&mut self, expires_at: Option<DateTime<Utc>>) {
+        self.expires_at = expires_at;
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+
+    /// Whether the user may log in and keep using their sessions.
+    pub fn is_active(&self) -> bool {
+        !self.disabled && !self.is_expired()
+    }
+
     pub fn check_password(&self, unknown: &str) -> bool {
         use argon2::{Argon2, password_hash::{PasswordVerifier, PasswordHash}};
 
@@ -28,58 +302,181 @@ impl User {
         }
 
         let argon2 = Argon2::default();
-        let parsed_hash = {
-            let hashed = self.hashed_password.as_ref().unwrap();
+        let parsed_hash = match PasswordHash::new(self.hashed_password.as_ref().unwrap()) {
+            Ok(parsed_hash) => parsed_hash,
+            Err(why) => {
+                log::warn!("unparseable password hash of user '{}': {}", self.username, why);
 
-            PasswordHash::new(hashed.as_str()).unwrap()
+                return false;
+            }
         };
 
         argon2.verify_password(unknown.as_bytes(), &parsed_hash).is_ok()
     }
 
-    pub fn prefixes(&self) -> &Vec<Arc<str>> {
+    pub fn prefixes(&self) -> impl Iterator<Item=&Arc<str>> {
+        self.file_prefixes.iter().map(FilePrefix::prefix)
+    }
+
+    pub fn file_prefixes(&self) -> &[FilePrefix] {
         &self.file_prefixes
     }
 
-    pub fn hash_password(&mut self) -> bool {
-        use argon2::{
-            password_hash::{
-                rand_core::OsRng,
-                PasswordHasher, SaltString,
-            },
-            Argon2,
+    /// The prefix entry `filename` was routed by, if any.
+    pub fn find_prefix(&self, filename: &str) -> Option<&FilePrefix> {
+        self.file_prefixes.iter().find(|x| filename.starts_with(x.prefix.as_ref()))
+    }
+
+    /// Whether uploads naming the user as recipient have to use one of
+    /// their prefixes anyway. That's the case when every prefix carries
+    /// limits, which a filename without a prefix would otherwise skip.
+    pub fn requires_prefix(&self) -> bool {
+        !self.file_prefixes.is_empty() && self.file_prefixes.iter().all(FilePrefix::has_limits)
+    }
+
+    pub fn quota(&self) -> Option<Quota> {
+        self.quota
+    }
+
+    pub fn roles(&self) -> &Vec<Arc<str>> {
+        &self.roles
+    }
+
+    pub fn groups(&self) -> &Vec<Arc<str>> {
+        &self.groups
+    }
+
+    pub fn set_prefixes(&mut self, prefixes: Vec<FilePrefix>) {
+        self.file_prefixes = prefixes;
+    }
+
+    pub fn set_roles(&mut self, roles: Vec<Arc<str>>) {
+        self.roles = roles;
+    }
+
+    pub fn set_groups(&mut self, groups: Vec<Arc<str>>) {
+        self.groups = groups;
+    }
+
+    pub fn set_password(&mut self, password: &str, config: &Config) -> Result<(), PasswordError> {
+        self.password = None;
+
+        config.password_policy()
+            .check(&self.username, password)
+            .map_err(PasswordError::Policy)?;
+
+        self.hash(password, config.argon2())
+    }
+
+    /// Hashes a password that is already in use with the current parameters,
+    /// skipping the password policy.
+    pub fn rehash_password(&mut self, password: &str, config: &Argon2Config) -> Result<(), PasswordError> {
+        self.hash(password, config)
+    }
+
+    /// Whether the stored hash was made with weaker parameters than the
+    /// configured ones, or isn't a parseable Argon2 hash at all.
+    pub fn needs_rehash(&self, config: &Argon2Config) -> bool {
+        use std::convert::TryFrom;
+
+        use argon2::{Params, password_hash::PasswordHash};
+
+        let hashed = match &self.hashed_password {
+            Some(hashed) => hashed,
+            None => return false,
         };
 
-        if self.password.is_none() {
-            return false;
+        PasswordHash::new(hashed.as_str())
+            .and_then(|hash| Params::try_from(&hash))
+            .map_or(true, |params| config.is_weaker(&params))
+    }
+
+    /// Hashes the plaintext `password` if there is one, rejecting passwords
+    /// that violate the configured policy. The plaintext is dropped either way.
+    pub fn hash_password(&mut self, config: &Config) -> Result<bool, PasswordError> {
+        match self.password.take() {
+            Some(password) => self.set_password(&password, config).map(|_| true),
+            None => Ok(false),
         }
+    }
 
-        let salt = SaltString::generate(&mut OsRng);
+    fn hash(&mut self, password: &str, config: &Argon2Config) -> Result<(), PasswordError> {
+        use argon2::password_hash::{
+            rand_core::OsRng,
+            PasswordHasher, SaltString,
+        };
 
-        let argon2 = Argon2::default();
+        let salt = SaltString::generate(&mut OsRng);
 
-        let password_hash = {
-            let plaintxt = self.password.as_ref().unwrap();
+        let argon2 = config.hasher();
 
-            argon2.hash_password(plaintxt.as_bytes(), &salt).unwrap().to_string()
-        };
+        let password_hash = argon2.hash_password(password.as_bytes(), &salt)
+            .map_err(|why| PasswordError::Hash(why.to_string()))?
+            .to_string();
 
         self.hashed_password = Some(password_hash);
-        self.password = None;
 
-        true
+        Ok(())
+    }
+
+    pub fn get_path_to_config_file(&self) -> PathBuf {
+        self.source.clone().unwrap_or_else(|| {
+            let mut path = crate::get_path_to_storage();
+
+            path.push("users");
+            path.push(format!("{}.toml", self.username));
+
+            path
+        })
+    }
+
+    /// Writes the user file through a temporary sibling and a rename, so a
+    /// crash mid-write never leaves a truncated user file behind.
+    pub fn save(&self) -> io::Result<()> {
+        let path = self.get_path_to_config_file();
+        let tmp_path = self.write_tmp_file(&path)?;
+
+        fs::rename(&tmp_path, &path)
+    }
+
+    /// Like `save`, but fails with `AlreadyExists` rather than replace a
+    /// file that's there already, e.g. one defining another user.
+    pub fn create(&self) -> io::Result<()> {
+        let path = self.get_path_to_config_file();
+        let tmp_path = self.write_tmp_file(&path)?;
+
+        // Unlike `rename`, linking fails if the target exists.
+        let linked = fs::hard_link(&tmp_path, &path);
+        let _ = fs::remove_file(&tmp_path);
+
+        linked
+    }
+
+    fn write_tmp_file(&self, path: &Path) -> io::Result<PathBuf> {
+        let tmp_path = path.with_extension("toml.tmp");
+
+        let serialized_data = toml::to_string(self)
+            .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))?;
+
+        fs::write(&tmp_path, serialized_data)?;
+
+        Ok(tmp_path)
+    }
+
+    pub fn delete(&self) -> io::Result<()> {
+        fs::remove_file(self.get_path_to_config_file())
     }
 
     pub fn get_path_to_user_file(&self, filename: impl AsRef<OsStr>) -> PathBuf {
         let mut path = self.get_path_to_user_folder();
 
-        path.push(filename.as_ref().to_str().expect("invalid filename").to_string());
+        path.push(filename.as_ref().to_str().expect("invalid filename"));
 
         path
     }
 
     pub fn get_path_to_user_folder(&self) -> PathBuf {
-        let mut path = PathBuf::from("storage");
+        let mut path = crate::get_path_to_storage();
 
         path.push("uploads");
 
@@ -90,8 +487,58 @@ impl User {
     }
 }
 
-pub fn get_users() -> Vec<User> {
-    fs::read_dir("storage/users")
+/// Checks a set of users for problems that would misroute uploads or break
+/// storage paths, returning one line per problem found.
+pub fn validate_users<'a>(users: impl IntoIterator<Item=&'a User>) -> Vec<String> {
+    let mut problems = vec![];
+
+    let mut usernames: HashMap<String, Arc<str>> = HashMap::new();
+    let mut prefixes: HashMap<String, (Arc<str>, Arc<str>)> = HashMap::new();
+
+    for user in users {
+        let username = user.username();
+
+        if !User::is_valid_username(&username) {
+            problems.push(format!("username {:?} is not safe as a folder name", username));
+        }
+
+        // Folders of users differing only in case clash on some filesystems.
+        if let Some(other) = usernames.insert(username.to_lowercase(), username.clone()) {
+            problems.push(format!("username '{}' collides with '{}'", username, other));
+        }
+
+        for prefix in user.prefixes() {
+            match prefix.strip_suffix('_') {
+                None => problems.push(format!("prefix '{}' of user '{}' is missing the trailing underscore", prefix, username)),
+                Some("") => problems.push(format!("prefix '{}' of user '{}' is empty", prefix, username)),
+                Some(body) if body.contains('_') => {
+                    // Filenames are split at the first underscore, so this can never match.
+                    problems.push(format!("prefix '{}' of user '{}' has an underscore before its end", prefix, username));
+                }
+                Some(_) => {}
+            }
+
+            let claim = (prefix.clone(), username.clone());
+
+            if let Some((other_prefix, owner)) = prefixes.insert(prefix.to_lowercase(), claim) {
+                if other_prefix == *prefix {
+                    problems.push(format!("prefix '{}' is claimed by both '{}' and '{}'", prefix, owner, username));
+                } else {
+                    problems.push(format!("prefix '{}' of user '{}' overlaps with '{}' of user '{}'", prefix, username, other_prefix, owner));
+                }
+            }
+        }
+    }
+
+    problems
+}
+
+/// Reads all user files from `storage/users`. Files that can't be read or
+/// parsed are skipped and reported next to the users that loaded fine.
+pub fn get_users(config: &Config) -> (Vec<User>, Vec<String>) {
+    let mut errors = vec![];
+
+    let users: Vec<User> = fs::read_dir(crate::get_path_to_storage().join("users"))
         .expect("couldn't exec storage/user folder")
         .filter_map(|maybe_file| {
             if let Ok(file) = maybe_file {
@@ -126,6 +573,7 @@ pub fn get_users() -> Vec<User> {
 
             if let Err(why) = &file_contents {
                 log::warn!("couldn't read user file data ({:?}): {}", file.path(), why);
+                errors.push(format!("couldn't read {:?}: {}", file.path(), why));
                 return None;
             }
 
@@ -137,21 +585,29 @@ pub fn get_users() -> Vec<User> {
 
             if let Err(why) = &data {
                 log::warn!("invalid user file schema ({:?}): {}", file.path(), why);
+                errors.push(format!("invalid schema in {:?}: {}", file.path(), why));
                 return None;
             }
 
             let (data, hashed_recently) = {
                 let mut data = data.unwrap();
 
-                let hashed_recently = data.hash_password();
+                data.source = Some(file.path());
+
+                let hashed_recently = data.hash_password(config);
 
                 (data, hashed_recently)
             };
 
-            if hashed_recently {
-                let serialized_data = toml::to_string(&data).unwrap();
+            if let Err(why) = &hashed_recently {
+                log::warn!("password in user file {:?} rejected: {}", file.path(), why);
+                errors.push(format!("password in {:?} rejected: {}", file.path(), why));
+                return None;
+            }
+
+            if hashed_recently.unwrap() {
                 log::info!("writing hashed password to {:?}", file.path());
-                let _ = fs::write(file.path(), serialized_data)
+                let _ = data.save()
                     .map_err(|why| {
                         log::warn!("failed to save updated user config {:?} - {}", file.path(), why);
                     });
@@ -161,5 +617,175 @@ pub fn get_users() -> Vec<User> {
 
             Some(data)
         })
-        .collect()
-}
\ No newline at end of file
+        .collect();
+
+    (users, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_plaintext_of_rejected_passwords() {
+        let mut user = User::new("user");
+        user.password = Some("user".to_string());
+
+        assert!(matches!(user.hash_password(&Config::default()), Err(PasswordError::Policy(_))));
+        assert!(user.password.is_none());
+        assert!(user.hashed_password.is_none());
+    }
+
+    #[test]
+    fn hashes_accepted_passwords() {
+        let mut user = User::new("user");
+        user.password = Some("change me, please".to_string());
+
+        assert!(user.hash_password(&Config::default()).unwrap());
+        assert!(user.password.is_none());
+        assert!(user.check_password("change me, please"));
+        assert!(!user.check_password("change me"));
+    }
+
+    #[test]
+    fn keeps_old_hash_when_policy_rejects() {
+        let mut user = User::new("user");
+
+        user.set_password("change me, please", &Config::default()).unwrap();
+
+        let error = user.set_password("short", &Config::default()).unwrap_err();
+
+        assert_eq!(error.status(), Status::UnprocessableEntity);
+        assert!(user.check_password("change me, please"));
+    }
+
+    #[test]
+    fn prefix_limits_are_camel_case() {
+        let prefix = rocket::serde::json::from_str::<FilePrefix>(
+            r#"{"prefix": "hw1_", "maxSize": "5MiB", "mimeTypes": ["application/pdf"]}"#
+        ).unwrap();
+
+        assert_eq!(prefix.max_size(), Some(ByteUnit::Mebibyte(5)));
+        assert!(prefix.allows_mime_type(Some("application/pdf")));
+
+        let json = rocket::serde::json::serde_json::to_value(&prefix).unwrap();
+
+        assert_eq!(json["maxSize"], 5 * 1024 * 1024);
+        assert_eq!(json["mimeTypes"][0], "application/pdf");
+        assert!(json.get("max_size").is_none());
+    }
+
+    #[test]
+    fn loads_snake_case_prefix_limits() {
+        let user = toml::from_str::<User>(r#"
+            username = "user"
+
+            [[file_prefixes]]
+            prefix = "hw1_"
+            max_size = "5MiB"
+            mime_types = ["application/pdf"]
+        "#).unwrap();
+
+        let prefix = &user.file_prefixes()[0];
+
+        assert_eq!(prefix.max_size(), Some(ByteUnit::Mebibyte(5)));
+        assert!(!prefix.allows_mime_type(Some("image/png")));
+    }
+
+    #[test]
+    fn plain_prefixes_stay_strings() {
+        let json = rocket::serde::json::serde_json::to_value(FilePrefix::new("user_")).unwrap();
+
+        assert_eq!(json, "user_");
+    }
+
+    #[test]
+    fn rehashes_hashes_weaker_than_configured() {
+        let cheap = toml::from_str::<Argon2Config>("memory_cost = 1024\ntime_cost = 1\nparallelism = 1").unwrap();
+        let configured = Argon2Config::default();
+
+        let mut user = User::new("user");
+        user.rehash_password("change me, please", &cheap).unwrap();
+
+        assert!(!user.needs_rehash(&cheap));
+        assert!(user.needs_rehash(&configured));
+
+        user.rehash_password("change me, please", &configured).unwrap();
+
+        assert!(!user.needs_rehash(&configured));
+        assert!(user.check_password("change me, please"));
+    }
+
+    #[test]
+    fn rehashes_unparseable_hashes() {
+        let user = toml::from_str::<User>(r#"
+            username = "user"
+            file_prefixes = []
+            hashed_password = "plain"
+        "#).unwrap();
+
+        assert!(user.needs_rehash(&Argon2Config::default()));
+    }
+
+    #[test]
+    fn creates_user_files_without_replacing_them() {
+        let folder = std::env::temp_dir().join(format!("dumpster-{}", crate::auth::new_token()));
+        fs::create_dir_all(&folder).unwrap();
+
+        let mut user = User::new("alice");
+        user.source = Some(folder.join("alice.toml"));
+
+        user.create().unwrap();
+        let created = fs::read_to_string(folder.join("alice.toml")).unwrap();
+
+        user.set_roles(vec!["admin".into()]);
+        let replaced = user.create();
+        let kept = fs::read_to_string(folder.join("alice.toml")).unwrap();
+        let leftovers = fs::read_dir(&folder).unwrap().count();
+
+        fs::remove_dir_all(&folder).unwrap();
+
+        assert_eq!(replaced.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(kept, created);
+        assert_eq!(leftovers, 1);
+    }
+
+    fn with_prefixes(username: &str, prefixes: &[&str]) -> User {
+        let mut user = User::new(username);
+        user.set_prefixes(prefixes.iter().map(|x| FilePrefix::new(*x)).collect());
+        user
+    }
+
+    #[test]
+    fn accepts_distinct_prefixes() {
+        let users = [with_prefixes("alice", &["alice_"]), with_prefixes("bob", &["bob_", "hw1_"])];
+
+        assert!(validate_users(&users).is_empty());
+    }
+
+    #[test]
+    fn reports_malformed_prefixes() {
+        let problems = validate_users(&[with_prefixes("alice", &["alice", "_", "hw_1_"])]);
+
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].contains("missing the trailing underscore"));
+        assert!(problems[1].contains("is empty"));
+        assert!(problems[2].contains("underscore before its end"));
+    }
+
+    #[test]
+    fn reports_colliding_users_and_prefixes() {
+        let problems = validate_users(&[
+            with_prefixes("alice", &["hw1_"]),
+            with_prefixes("Alice", &["HW1_"]),
+            with_prefixes("bob", &["hw2_"]),
+            with_prefixes("carol", &["hw2_"]),
+        ]);
+
+        assert_eq!(problems, [
+            "username 'Alice' collides with 'alice'",
+            "prefix 'HW1_' of user 'Alice' overlaps with 'hw1_' of user 'alice'",
+            "prefix 'hw2_' is claimed by both 'bob' and 'carol'",
+        ]);
+    }
+}